@@ -0,0 +1,233 @@
+use crate::simulate;
+
+pub const USAGE: &str = "Usage: grok [OPTIONS] [COMMAND]
+
+Commands:
+\trun                  replies to mentions, the default
+\tinit [--force]       creates the default config file, --force overwrites an existing one.
+\t                     -c does the same
+\tcheck                validates the config and the credentials
+\tsimulate             shows replies to posts without connecting, see grok simulate -h
+\twhoami               prints the account the bot is logged in as
+\tpost [--] [TEXT]     posts TEXT, or stdin if it's missing, as the bot. Everything after --
+\t                     is posted as is, even if it looks like an option
+
+Options:
+\t--config PATH        config file to use, ./config.toml by default
+\t--account NAME       only use the account called NAME from the config, all of them by default
+\t--dry-run            don't post, mark notifications as read or save the state, print the
+\t                     requests that would be made as json lines instead
+\t--dry-run-output PATH
+\t                     append the dry run requests to PATH instead of printing them, implies
+\t                     --dry-run
+\t--once               check the notifications once and exit instead of running forever
+\t-v, --verbose        print more about what the bot is doing
+\t-h, --help           displays this menu
+
+Exit codes:
+\t1                    invalid config or another error
+\t2                    the instance rejected the token
+\t3                    the instance couldn't be reached within startup_timeout";
+
+#[derive(Debug)]
+pub enum Command {
+    Run,
+    Init {
+        ///Overwrite the config if it already exists
+        force: bool,
+    },
+    Check,
+    Simulate(simulate::Options),
+    Whoami,
+    Post {
+        ///Read from stdin if `None`
+        text: Option<String>,
+    },
+    Help,
+}
+
+///Flags that change how the bot runs
+#[derive(Default, Debug, Clone)]
+pub struct RunOptions {
+    pub dry_run: bool,
+    ///File the replies of a dry run are appended to as json lines, stdout if `None`
+    pub dry_run_output: Option<String>,
+    pub once: bool,
+    pub verbose: bool,
+    ///Only use the account with this name, all of them if `None`
+    pub account: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct Cli {
+    pub command: Command,
+    pub config: String,
+    pub options: RunOptions,
+}
+
+impl Cli {
+    ///Parses the arguments, without the program name
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut config = "./config.toml".to_string();
+        let mut options = RunOptions::default();
+        let mut help = false;
+        //Everything that isn't a global option, starting with the command
+        let mut rest = Vec::new();
+
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => config = args.next().ok_or("--config needs a path")?.clone(),
+                //Kept from before there were commands
+                "-c" if rest.is_empty() => rest.push("init".into()),
+                "--dry-run" => options.dry_run = true,
+                "--dry-run-output" => {
+                    options.dry_run = true;
+                    options.dry_run_output =
+                        Some(args.next().ok_or("--dry-run-output needs a path")?.clone());
+                }
+                "--account" => {
+                    options.account = Some(args.next().ok_or("--account needs a name")?.clone())
+                }
+                "--once" => options.once = true,
+                "-v" | "--verbose" => options.verbose = true,
+                //Simulate has its own help
+                "-h" | "--help" if rest.first().is_none_or(|c| c != "simulate") => help = true,
+                "--" => rest.extend(args.by_ref().cloned()),
+                _ => rest.push(arg.clone()),
+            }
+        }
+
+        if help {
+            return Ok(Self {
+                command: Command::Help,
+                config,
+                options,
+            });
+        }
+
+        let no_args = |rest: &[String]| match rest.first() {
+            Some(a) => Err(format!("unexpected argument `{a}`")),
+            None => Ok(()),
+        };
+
+        let command = match rest.first().map(String::as_str) {
+            None | Some("run") => {
+                no_args(rest.get(1..).unwrap_or_default())?;
+                Command::Run
+            }
+            Some("init") => match &rest[1..] {
+                [] => Command::Init { force: false },
+                [f] if f == "--force" => Command::Init { force: true },
+                r => return Err(format!("unexpected argument `{}`", r[0])),
+            },
+            Some("check") => {
+                no_args(&rest[1..])?;
+                Command::Check
+            }
+            Some("simulate") => Command::Simulate(simulate::Options::parse(&rest[1..])?),
+            Some("whoami") => {
+                no_args(&rest[1..])?;
+                Command::Whoami
+            }
+            Some("post") => Command::Post {
+                text: (rest.len() > 1).then(|| rest[1..].join(" ")),
+            },
+            Some("help") => Command::Help,
+            Some(c) => return Err(format!("unknown command `{c}`")),
+        };
+
+        Ok(Self {
+            command,
+            config,
+            options,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, String> {
+        Cli::parse(&args.iter().map(|i| i.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert!(matches!(parse(&[]).unwrap().command, Command::Run));
+        assert!(matches!(parse(&["run"]).unwrap().command, Command::Run));
+        assert!(matches!(parse(&["check"]).unwrap().command, Command::Check));
+        assert!(matches!(
+            parse(&["init", "--force"]).unwrap().command,
+            Command::Init { force: true }
+        ));
+        assert!(matches!(
+            parse(&["-c"]).unwrap().command,
+            Command::Init { force: false }
+        ));
+    }
+
+    #[test]
+    fn parses_options() {
+        let cli = parse(&[
+            "--config",
+            "bot.toml",
+            "--account",
+            "main",
+            "--dry-run-output",
+            "out.jsonl",
+            "--once",
+            "-v",
+        ])
+        .unwrap();
+
+        assert!(matches!(cli.command, Command::Run));
+        assert_eq!(cli.config, "bot.toml");
+        assert_eq!(cli.options.account.as_deref(), Some("main"));
+        assert!(cli.options.dry_run);
+        assert_eq!(cli.options.dry_run_output.as_deref(), Some("out.jsonl"));
+        assert!(cli.options.once);
+        assert!(cli.options.verbose);
+
+        assert!(parse(&["--config"]).is_err());
+        assert!(parse(&["--account"]).is_err());
+    }
+
+    #[test]
+    fn parses_post_text() {
+        let text = |args: &[&str]| match parse(args).unwrap().command {
+            Command::Post { text } => text,
+            c => panic!("expected post, got {c:?}"),
+        };
+
+        assert_eq!(text(&["post"]), None);
+        assert_eq!(
+            text(&["post", "hello", "world"]).as_deref(),
+            Some("hello world")
+        );
+        assert_eq!(
+            text(&["post", "--", "--once", "-h"]).as_deref(),
+            Some("--once -h")
+        );
+    }
+
+    #[test]
+    fn help_wins_over_commands() {
+        assert!(matches!(parse(&["-h"]).unwrap().command, Command::Help));
+        assert!(matches!(
+            parse(&["post", "hello", "--help"]).unwrap().command,
+            Command::Help
+        ));
+        assert!(matches!(parse(&["help"]).unwrap().command, Command::Help));
+    }
+
+    #[test]
+    fn rejects_unknown_arguments() {
+        assert!(parse(&["frobnicate"]).is_err());
+        assert!(parse(&["check", "extra"]).is_err());
+        assert!(parse(&["init", "--forced"]).is_err());
+        assert!(parse(&["run", "-x"]).is_err());
+    }
+}
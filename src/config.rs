@@ -0,0 +1,607 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use toml::Spanned;
+
+use crate::fediverse::Backend;
+use crate::responses::{Response, RngSource, Rules, Selection, Word};
+
+///`instance`, `token` and `polling_interval` can be overridden by the `GROK_INSTANCE`, `GROK_TOKEN`
+///and `GROK_POLLING_INTERVAL` environment variables, which are also read from a `.env` file
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Config {
+    ///Name of the bot in the logs
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    ///Instance URL
+    #[serde(default)]
+    pub instance: String,
+    ///Which api to use to talk to the instance
+    #[serde(default)]
+    pub backend: Backend,
+    ///Api access token, can be left out if `token_file` or `GROK_TOKEN` is set
+    #[serde(default)]
+    pub token: String,
+    ///File to read the token from instead, like `/run/secrets/grok_token`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_file: Option<String>,
+    ///How much time to wait before checking notifications again
+    #[serde(default = "default_polling_interval")]
+    pub polling_interval: u64,
+    ///How many seconds to keep retrying at startup if the instance can't be reached before exiting
+    ///with code 3. With several accounts it's retried for as long as the others run
+    #[serde(default = "default_startup_timeout")]
+    pub startup_timeout: u64,
+    ///Where to store which notifications were already processed
+    #[serde(default = "default_state_file")]
+    pub state_file: String,
+    ///Receive mentions through the streaming api instead of polling, if the backend supports it
+    #[serde(default)]
+    pub streaming: bool,
+    ///How the response used for a reply is picked
+    #[serde(default)]
+    pub selection: Selection,
+    ///Seed for the random generator, makes the replies reproducible
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+    ///Derive the seed of every reply from `seed` and the id of the post, so the same post always
+    ///gets the same reply
+    #[serde(default)]
+    pub seed_per_status: bool,
+    ///Named word lists that templates in the responses can pick from
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub lists: HashMap<String, Vec<Word>>,
+    ///Things the bot can respond with
+    #[serde(default)]
+    pub responses: Vec<Response>,
+    ///Run several bots from one process, each on its own account. The settings above are shared by
+    ///all of them and only used as a bot themselves if there are no accounts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub accounts: Vec<AccountConfig>,
+}
+
+///A bot running on its own account, every setting left out is taken from the top of the config
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AccountConfig {
+    ///Shown in the logs, has to be unique
+    pub name: String,
+    pub instance: Option<String>,
+    pub backend: Option<Backend>,
+    pub token: Option<String>,
+    pub token_file: Option<String>,
+    pub polling_interval: Option<u64>,
+    pub startup_timeout: Option<u64>,
+    ///`./state_NAME.json` by default, accounts can't share one
+    pub state_file: Option<String>,
+    pub streaming: Option<bool>,
+    pub selection: Option<Selection>,
+    pub seed: Option<u64>,
+    pub seed_per_status: Option<bool>,
+    ///The shared responses are used if there are none
+    pub responses: Option<Vec<Response>>,
+}
+
+///Only used to find which line each response starts at
+#[derive(Deserialize)]
+struct ResponseSpans {
+    #[serde(default)]
+    responses: Vec<Spanned<toml::Table>>,
+    #[serde(default)]
+    accounts: Vec<AccountSpans>,
+}
+
+#[derive(Deserialize)]
+struct AccountSpans {
+    #[serde(default)]
+    responses: Vec<Spanned<toml::Table>>,
+}
+
+impl Config {
+    ///Reads and validates the config file, returning the config of every account along with its
+    ///compiled responses. All the problems found are reported at once
+    pub fn load(path: &str) -> Result<Vec<(Self, Rules)>, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+
+        let mut config: Config = toml::from_str(&text).map_err(|e| format!("{path}: {e}"))?;
+
+        let mut errors = config.apply_env();
+
+        let spans = toml::from_str::<ResponseSpans>(&text).ok();
+        let lines = |responses: &[Spanned<toml::Table>]| {
+            responses
+                .iter()
+                .map(|i| text[..i.span().start].matches('\n').count() + 1)
+                .collect::<Vec<_>>()
+        };
+
+        let top_lines = spans
+            .as_ref()
+            .map(|s| lines(&s.responses))
+            .unwrap_or_default();
+
+        //Accounts without their own responses point at the shared ones
+        let mut accounts = Vec::new();
+
+        if config.accounts.is_empty() {
+            accounts.push((String::new(), config.clone(), top_lines));
+        } else {
+            let mut names = HashSet::new();
+            let mut state_files = HashSet::new();
+
+            for (ind, a) in config.accounts.iter().enumerate() {
+                let prefix = format!("accounts[{ind}] ({})", a.name);
+
+                if a.name.is_empty() {
+                    errors.push(format!("accounts[{ind}]: name is empty"));
+                } else if !names.insert(&a.name) {
+                    errors.push(format!("{prefix}: the name is used more than once"));
+                }
+
+                let resolved = config.resolve(a);
+
+                if !state_files.insert(resolved.state_file.clone()) {
+                    errors.push(format!(
+                        "{prefix}: state_file {} is used by another account",
+                        resolved.state_file
+                    ));
+                }
+
+                let lines = match a.responses {
+                    Some(_) => spans
+                        .as_ref()
+                        .and_then(|s| s.accounts.get(ind))
+                        .map(|s| lines(&s.responses))
+                        .unwrap_or_default(),
+                    None => top_lines.clone(),
+                };
+
+                accounts.push((format!("{prefix}: "), resolved, lines));
+            }
+        }
+
+        let mut loaded = Vec::new();
+
+        for (prefix, mut config, lines) in accounts {
+            errors.extend(config.read_token().into_iter().map(|e| prefix.clone() + &e));
+
+            //A markov seed replaces the generator of the reply, so one of them would be ignored
+            if config.seed.is_some() || config.seed_per_status {
+                for (ind, r) in config.responses.iter().enumerate() {
+                    if r.markov.as_ref().is_some_and(|m| m.seed.is_some()) {
+                        let at = match lines.get(ind) {
+                            Some(line) => format!("responses[{ind}] (line {line})"),
+                            None => format!("responses[{ind}]"),
+                        };
+
+                        errors.push(format!(
+                            "{prefix}{at}: markov.seed can't be used together with seed or \
+                             seed_per_status"
+                        ));
+                    }
+                }
+            }
+
+            match Rules::compile(&config.responses, &config.lists, config.selection, &lines) {
+                Ok(mut rules) => {
+                    rules.rng = RngSource::new(config.seed, config.seed_per_status);
+                    loaded.push((config, rules));
+                }
+                Err(e) => errors.extend(e.into_iter().map(|e| prefix.clone() + &e)),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(loaded)
+        } else {
+            Err(errors
+                .iter()
+                .map(|i| format!("{path}: {i}"))
+                .collect::<Vec<_>>()
+                .join("\n"))
+        }
+    }
+
+    ///Applies the environment variables, which take precedence over the config and the token file
+    fn apply_env(&mut self) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if let Ok(instance) = std::env::var("GROK_INSTANCE") {
+            self.instance = instance;
+        }
+
+        if let Ok(interval) = std::env::var("GROK_POLLING_INTERVAL") {
+            match interval.parse() {
+                Ok(i) => self.polling_interval = i,
+                Err(e) => errors.push(format!("GROK_POLLING_INTERVAL: {e}")),
+            }
+        }
+
+        if let Ok(token) = std::env::var("GROK_TOKEN") {
+            self.token = token;
+            self.token_file = None;
+        }
+
+        errors
+    }
+
+    ///Reads the token file and checks that the instance and the token are set
+    fn read_token(&mut self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let mut token_file_failed = false;
+
+        if let Some(file) = &self.token_file {
+            match std::fs::read_to_string(file) {
+                Ok(t) => self.token = t.trim().into(),
+                Err(e) => {
+                    errors.push(format!("token_file: failed to read {file}: {e}"));
+                    token_file_failed = true;
+                }
+            }
+        }
+
+        if self.instance.is_empty() {
+            errors.push("instance is missing, set it or GROK_INSTANCE".into());
+        }
+
+        if self.token.is_empty() && !token_file_failed {
+            errors.push("token is missing, set it, token_file or GROK_TOKEN".into());
+        }
+
+        errors
+    }
+
+    ///Returns the config of the account, taking whatever it doesn't set from this one
+    fn resolve(&self, account: &AccountConfig) -> Config {
+        //Setting a token in the account shouldn't still read the shared token file
+        let token_file = match (&account.token, &account.token_file) {
+            (_, Some(f)) => Some(f.clone()),
+            (Some(_), None) => None,
+            (None, None) => self.token_file.clone(),
+        };
+
+        Config {
+            name: account.name.clone(),
+            instance: account.instance.clone().unwrap_or(self.instance.clone()),
+            backend: account.backend.unwrap_or(self.backend),
+            token: account.token.clone().unwrap_or(self.token.clone()),
+            token_file,
+            polling_interval: account.polling_interval.unwrap_or(self.polling_interval),
+            startup_timeout: account.startup_timeout.unwrap_or(self.startup_timeout),
+            state_file: account
+                .state_file
+                .clone()
+                .unwrap_or(format!("./state_{}.json", account.name)),
+            streaming: account.streaming.unwrap_or(self.streaming),
+            selection: account.selection.unwrap_or(self.selection),
+            seed: account.seed.or(self.seed),
+            seed_per_status: account.seed_per_status.unwrap_or(self.seed_per_status),
+            lists: self.lists.clone(),
+            responses: account.responses.clone().unwrap_or(self.responses.clone()),
+            accounts: Vec::new(),
+        }
+    }
+
+    ///Prefix for log lines about this bot, empty if it has no name
+    pub fn tag(&self) -> String {
+        if self.name.is_empty() {
+            String::new()
+        } else {
+            format!("[{}] ", self.name)
+        }
+    }
+}
+
+//Written by hand so the token never ends up in logs
+impl std::fmt::Debug for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        //Destructured so new fields can't be forgotten here
+        let Self {
+            name,
+            instance,
+            backend,
+            token: _,
+            token_file,
+            polling_interval,
+            startup_timeout,
+            state_file,
+            streaming,
+            selection,
+            seed,
+            seed_per_status,
+            lists,
+            responses,
+            accounts,
+        } = self;
+
+        f.debug_struct("Config")
+            .field("name", name)
+            .field("instance", instance)
+            .field("backend", backend)
+            .field("token", &"<redacted>")
+            .field("token_file", token_file)
+            .field("polling_interval", polling_interval)
+            .field("startup_timeout", startup_timeout)
+            .field("state_file", state_file)
+            .field("streaming", streaming)
+            .field("selection", selection)
+            .field("seed", seed)
+            .field("seed_per_status", seed_per_status)
+            .field("lists", lists)
+            .field("responses", responses)
+            .field("accounts", accounts)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for AccountConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        //Destructured so new fields can't be forgotten here
+        let Self {
+            name,
+            instance,
+            backend,
+            token,
+            token_file,
+            polling_interval,
+            startup_timeout,
+            state_file,
+            streaming,
+            selection,
+            seed,
+            seed_per_status,
+            responses,
+        } = self;
+
+        f.debug_struct("AccountConfig")
+            .field("name", name)
+            .field("instance", instance)
+            .field("backend", backend)
+            .field("token", &token.as_ref().map(|_| "<redacted>"))
+            .field("token_file", token_file)
+            .field("polling_interval", polling_interval)
+            .field("startup_timeout", startup_timeout)
+            .field("state_file", state_file)
+            .field("streaming", streaming)
+            .field("selection", selection)
+            .field("seed", seed)
+            .field("seed_per_status", seed_per_status)
+            .field("responses", responses)
+            .finish()
+    }
+}
+
+pub fn default_polling_interval() -> u64 {
+    10
+}
+
+pub fn default_state_file() -> String {
+    "./state.json".into()
+}
+
+pub fn default_startup_timeout() -> u64 {
+    300
+}
+
+pub fn generate_default_config() -> Config {
+    Config {
+        name: String::new(),
+        instance: "https://test.com".into(),
+        backend: Backend::auto,
+        token: "API_TOKEN".into(),
+        token_file: None,
+        polling_interval: default_polling_interval(),
+        startup_timeout: default_startup_timeout(),
+        state_file: default_state_file(),
+        streaming: false,
+        selection: Selection::sequential,
+        seed: None,
+        seed_per_status: false,
+        lists: HashMap::new(),
+        responses: vec![
+            Response {
+                regex: Some(r"(@.*)*is this true\?".into()),
+                chance: 100,
+                min_words: 1,
+                max_words: 1,
+                contains_emoji: true,
+                words: vec![
+                    "meow !!!".into(),
+                    " ‌:neocat_sign_yes:".into(),
+                    " ‌:neocat_sign_no:".into(),
+                    "nyaa ?".into(),
+                    " ‌:neocat_confused:".into(),
+                    " ‌:neocat_glare:".into(),
+                    " ‌:neocat_woozy:".into(),
+                    " ‌:neocat_googly_woozy:".into(),
+                ],
+                markov: None,
+                llm: None,
+                parent: None,
+                react: false,
+            },
+            Response {
+                regex: None,
+                chance: 10,
+                min_words: 1,
+                max_words: 10,
+                contains_emoji: true,
+                words: vec![
+                    "waf".into(),
+                    "arrf".into(),
+                    "awrfaf".into(),
+                    ":neofox_floof:".into(),
+                    ":fluffy_tail:".into(),
+                ],
+                markov: None,
+                llm: None,
+                parent: None,
+                react: false,
+            },
+            Response {
+                regex: None,
+                chance: 100,
+                min_words: 1,
+                max_words: 10,
+                contains_emoji: false,
+                words: vec![
+                    "meow".into(),
+                    "mew".into(),
+                    "nyaaa".into(),
+                    ":3".into(),
+                    "mrrp".into(),
+                    "mrmeow".into(),
+                    ":neocat_floof:".into(),
+                    ":neocat_flop:".into(),
+                ],
+                markov: None,
+                llm: None,
+                parent: None,
+                react: false,
+            },
+        ],
+        accounts: Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRETS: &str = r#"
+        instance = "https://example.com"
+        token = "top-secret-token"
+
+        [[responses]]
+        chance = 100
+        min_words = 1
+        max_words = 1
+        contains_emoji = false
+        words = ["meow"]
+        llm = { endpoint = "http://localhost:8080", model = "m", api_key = "top-secret-key" }
+
+        [[accounts]]
+        name = "cat"
+        token = "account-secret-token"
+
+        [[accounts.responses]]
+        chance = 100
+        min_words = 1
+        max_words = 1
+        contains_emoji = false
+        words = ["mew"]
+        llm = { endpoint = "http://localhost:8080", model = "m", api_key = "account-secret-key" }
+    "#;
+
+    #[test]
+    fn debug_output_is_redacted() {
+        let config: Config = toml::from_str(SECRETS).unwrap();
+        let account = &config.accounts[0];
+        let llm = config.responses[0].llm.as_ref().unwrap();
+
+        for debug in [
+            format!("{config:?}"),
+            format!("{config:#?}"),
+            format!("{account:?}"),
+            format!("{llm:?}"),
+            format!("{:?}", config.resolve(account)),
+        ] {
+            assert!(!debug.contains("secret"), "{debug}");
+            assert!(debug.contains("<redacted>"), "{debug}");
+        }
+    }
+
+    ///Loads `text` through a file, like the bot does
+    fn load(name: &str, text: &str) -> Result<Vec<(Config, Rules)>, String> {
+        let path =
+            std::env::temp_dir().join(format!("grok-test-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, text).unwrap();
+
+        let loaded = Config::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        loaded
+    }
+
+    #[test]
+    fn reports_every_invalid_response_with_its_line() {
+        let errors = load(
+            "invalid",
+            r#"instance = "https://example.com"
+token = "token"
+
+[[responses]]
+chance = 100
+regex = "(unclosed"
+min_words = 1
+max_words = 1
+contains_emoji = false
+words = ["meow"]
+
+[[responses]]
+chance = 150
+min_words = 5
+max_words = 2
+contains_emoji = false
+words = []
+"#,
+        )
+        .unwrap_err();
+
+        //The regex error spans several lines
+        let errors = errors
+            .lines()
+            .filter(|i| i.contains(".toml: "))
+            .collect::<Vec<_>>();
+
+        assert_eq!(errors.len(), 4, "{errors:#?}");
+        assert!(errors[0].contains("responses[0] (line 4): invalid regex"));
+        assert!(errors[1].contains("responses[1] (line 12): chance is 150"));
+        assert!(errors[2].contains("responses[1] (line 12): min_words (5) is greater"));
+        assert!(errors[3].contains("responses[1] (line 12): words is empty"));
+    }
+
+    #[test]
+    fn reports_the_lines_of_account_responses() {
+        let errors = load(
+            "accounts",
+            r#"instance = "https://example.com"
+token = "token"
+
+[[responses]]
+chance = 100
+min_words = 1
+max_words = 1
+contains_emoji = false
+words = ["meow"]
+
+[[accounts]]
+name = "fox"
+
+[[accounts.responses]]
+chance = 100
+min_words = 1
+max_words = 1
+contains_emoji = false
+words = ["{missing}"]
+"#,
+        )
+        .unwrap_err();
+
+        assert!(
+            errors.contains("accounts[0] (fox): responses[0] (line 14): `{missing}` uses the unknown list `missing`"),
+            "{errors}"
+        );
+    }
+
+    #[test]
+    fn loads_a_valid_config() {
+        let loaded = load(
+            "valid",
+            &toml::to_string(&generate_default_config()).unwrap(),
+        )
+        .unwrap();
+
+        assert_eq!(loaded.len(), 1);
+        assert_eq!(loaded[0].1.rules.len(), loaded[0].0.responses.len());
+    }
+}
@@ -0,0 +1,199 @@
+use std::fmt::Display;
+use std::time::Duration;
+
+use reqwest::{Response, StatusCode};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+///How much of the response is kept around the position a deserialization error happened at
+const SNIPPET_RADIUS: usize = 100;
+
+///Error returned by the api clients
+#[derive(Debug)]
+pub enum ClientError {
+    ///The request failed before getting a response, DNS, TLS, timeouts, dropped connections...
+    Transport(reqwest::Error),
+    ///The token is invalid or lacks the permissions for the request
+    Unauthorized {
+        status: StatusCode,
+        message: Option<String>,
+    },
+    ///Too many requests were made
+    RateLimited {
+        ///How long the server asked to wait, if it said
+        retry_after: Option<Duration>,
+        message: Option<String>,
+    },
+    ///Any other unsuccessful status
+    Status {
+        status: StatusCode,
+        ///Misskey's error code, like `NO_SUCH_NOTE`
+        code: Option<String>,
+        message: Option<String>,
+    },
+    ///The response didn't match the expected type
+    Deserialize {
+        error: serde_json::Error,
+        ///Part of the response around where parsing failed
+        snippet: String,
+    },
+}
+
+impl ClientError {
+    ///Whether making the same request again later could succeed
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Transport(_) | Self::RateLimited { .. } => true,
+            Self::Status { status, .. } => status.is_server_error(),
+            Self::Unauthorized { .. } | Self::Deserialize { .. } => false,
+        }
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Transport(e) => write!(f, "request failed: {e}"),
+            Self::Unauthorized { status, message } => {
+                write!(f, "unauthorized ({status})")?;
+                if let Some(m) = message {
+                    write!(f, ": {m}")?;
+                }
+                Ok(())
+            }
+            Self::RateLimited {
+                retry_after,
+                message,
+            } => {
+                write!(f, "rate limited")?;
+                if let Some(r) = retry_after {
+                    write!(f, ", retry after {}s", r.as_secs())?;
+                }
+                if let Some(m) = message {
+                    write!(f, ": {m}")?;
+                }
+                Ok(())
+            }
+            Self::Status {
+                status,
+                code,
+                message,
+            } => {
+                write!(f, "server returned {status}")?;
+                if let Some(c) = code {
+                    write!(f, " {c}")?;
+                }
+                if let Some(m) = message {
+                    write!(f, ": {m}")?;
+                }
+                Ok(())
+            }
+            Self::Deserialize { error, snippet } => {
+                write!(f, "failed to parse the response: {error}, near `{snippet}`")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(value: reqwest::Error) -> Self {
+        Self::Transport(value)
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct MisskeyError {
+    code: Option<String>,
+    message: Option<String>,
+}
+
+///Error bodies of both apis
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ErrorBody {
+    ///`{"error": {"code": ..., "message": ...}}`
+    Misskey { error: MisskeyError },
+    ///`{"error": ..., "error_description": ...}`
+    Mastodon {
+        error: String,
+        error_description: Option<String>,
+    },
+}
+
+///Turns an unsuccessful response into an error, passing successful ones through
+pub async fn check_response(response: Response) -> Result<Response, ClientError> {
+    let status = response.status();
+
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    let retry_after = response
+        .headers()
+        .get("Retry-After")
+        .and_then(|i| i.to_str().ok())
+        .and_then(|i| i.parse::<f64>().ok())
+        .map(|i| Duration::from_secs_f64(i.max(0.0)));
+
+    let body = response.text().await.unwrap_or_default();
+
+    let (code, message) = match serde_json::from_str::<ErrorBody>(&body) {
+        Ok(ErrorBody::Misskey { error }) => (error.code, error.message),
+        Ok(ErrorBody::Mastodon {
+            error,
+            error_description,
+        }) => (
+            None,
+            Some(match error_description {
+                Some(d) => format!("{error} ({d})"),
+                None => error,
+            }),
+        ),
+        Err(_) => (None, None),
+    };
+
+    if status == StatusCode::TOO_MANY_REQUESTS || code.as_deref() == Some("RATE_LIMIT_EXCEEDED") {
+        return Err(ClientError::RateLimited {
+            retry_after,
+            message,
+        });
+    }
+
+    if status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN {
+        return Err(ClientError::Unauthorized { status, message });
+    }
+
+    Err(ClientError::Status {
+        status,
+        code,
+        message,
+    })
+}
+
+///Checks the response and parses its body as json
+pub async fn parse_response<T: DeserializeOwned>(response: Response) -> Result<T, ClientError> {
+    let text = check_response(response).await?.text().await?;
+
+    parse_json(&text)
+}
+
+///Parses json, keeping the offending part of the text on failure
+pub fn parse_json<T: DeserializeOwned>(text: &str) -> Result<T, ClientError> {
+    serde_json::from_str(text).map_err(|error| {
+        let line = text
+            .lines()
+            .nth(error.line().saturating_sub(1))
+            .unwrap_or("");
+        let column = error.column().saturating_sub(1);
+
+        let snippet = line
+            .chars()
+            .skip(column.saturating_sub(SNIPPET_RADIUS))
+            .take(SNIPPET_RADIUS * 2)
+            .collect();
+
+        ClientError::Deserialize { error, snippet }
+    })
+}
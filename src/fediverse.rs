@@ -0,0 +1,159 @@
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ClientError;
+use crate::request::RateLimit;
+
+#[derive(Default, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+//For proper serialization
+#[allow(non_camel_case_types)]
+pub enum Backend {
+    ///Pick the api from the software the instance reports through nodeinfo, mastodon if it
+    ///doesn't
+    #[default]
+    auto,
+    ///Talk to the instance through the mastodon api
+    mastodon,
+    ///Talk to the instance through the misskey api
+    misskey,
+    ///Talk to the instance through the mastodon api with the pleroma extensions, for pleroma and
+    ///akkoma
+    pleroma,
+}
+
+///Visibility of a post, independent of the platform
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    #[default]
+    Public,
+    ///Public, but not shown on the public timelines
+    Unlisted,
+    Followers,
+    ///Only visible to the mentioned users
+    Direct,
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Account {
+    pub id: String,
+    pub username: String,
+    ///Instance of the account, `None` if the account is local
+    pub host: Option<String>,
+    pub bot: bool,
+}
+
+impl Account {
+    ///Returns the handle used to ping the account, `user` or `user@instance`
+    pub fn handle(&self) -> String {
+        match &self.host {
+            Some(host) => format!("{}@{host}", self.username),
+            None => self.username.clone(),
+        }
+    }
+}
+
+///Kind of a file attached to a post
+#[derive(Default, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+//For proper serialization
+#[allow(non_camel_case_types)]
+pub enum MediaKind {
+    image,
+    ///Videos and gifs
+    video,
+    audio,
+    #[default]
+    other,
+}
+
+///What a stream of mentions yields
+#[derive(Debug)]
+pub enum MentionEvent {
+    ///The stream connected, mentions that arrived before that have to be fetched by polling
+    Connected,
+    Mention(Box<Mention>),
+    ///A mention arrived but couldn't be read, it has to be fetched by polling
+    Missed(ClientError),
+}
+
+///A post that mentioned the bot
+#[derive(Default, Debug, Clone)]
+pub struct Mention {
+    ///Id of the notification
+    pub id: String,
+    ///Id of the post that mentioned the bot
+    pub status_id: String,
+    pub author: Account,
+    pub text: Option<String>,
+    pub visibility: Visibility,
+    ///All the accounts mentioned in the post, may include the bot itself
+    pub mentions: Vec<Account>,
+    ///Id of the post it replied to
+    pub in_reply_to_id: Option<String>,
+    ///Id of the chat the mention was sent in, `None` for posts. Only pleroma has chats
+    pub chat_id: Option<String>,
+}
+
+///A post in a thread
+#[derive(Default, Debug, Clone)]
+pub struct Post {
+    pub id: String,
+    pub author: Account,
+    pub text: Option<String>,
+    pub in_reply_to_id: Option<String>,
+    ///Kinds of the attached files
+    pub media: Vec<MediaKind>,
+    ///Choices of the poll, if the post has one
+    pub poll: Option<Vec<String>>,
+}
+
+///A request a client would make, written out instead of sent on dry runs
+#[derive(Serialize, Debug)]
+pub struct Payload {
+    ///Api path the request would be sent to
+    pub endpoint: String,
+    pub body: serde_json::Value,
+}
+
+///Everything the bot needs from an instance, implemented once per platform
+#[async_trait]
+pub trait FediverseClient: Send + Sync {
+    ///Returns the account the client is authenticated as
+    async fn whoami(&self) -> Result<Account, ClientError>;
+
+    ///Fetches the posts that mentioned the bot after the notification `since_id`, newest first
+    async fn fetch_mentions(&self, since_id: Option<String>) -> Result<Vec<Mention>, ClientError>;
+
+    ///Fetches a single post
+    async fn fetch_post(&self, id: String) -> Result<Post, ClientError>;
+
+    ///Fetches the posts the mention replied to, oldest first
+    async fn fetch_thread(&self, mention: &Mention) -> Result<Vec<Post>, ClientError>;
+
+    ///Posts `text` publicly, not as a reply to anything
+    async fn post(&self, text: String) -> Result<(), ClientError>;
+
+    ///Posts `text` as a reply to the mention, with the same visibility
+    async fn reply(&self, mention: &Mention, text: String) -> Result<(), ClientError>;
+
+    ///Returns what `reply` would send, without sending it
+    fn reply_payload(&self, mention: &Mention, text: String) -> Payload;
+
+    ///Returns the request budget left, so low priority work can be put off
+    fn rate_limit(&self) -> RateLimit;
+
+    ///Marks the notifications up to `last_id` as read, without deleting any of them so mentions
+    ///that arrive meanwhile aren't lost
+    async fn mark_read(&self, last_id: String) -> Result<(), ClientError>;
+
+    ///Reacts to the mention with an emoji
+    async fn react(&self, mention: &Mention, reaction: String) -> Result<(), ClientError>;
+
+    ///Returns what `react` would send, without sending it
+    fn react_payload(&self, mention: &Mention, reaction: String) -> Payload;
+
+    ///Returns a stream of mentions as they arrive, `None` if the platform can't stream them
+    fn stream_mentions(&self) -> Option<BoxStream<'static, MentionEvent>> {
+        None
+    }
+}
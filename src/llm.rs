@@ -0,0 +1,297 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ClientError, parse_response};
+use crate::responses::Context;
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct LlmOptions {
+    ///Base url of an OpenAI compatible server, like `http://localhost:11434` for ollama or
+    ///`http://localhost:8080` for llama.cpp
+    pub endpoint: String,
+    pub model: String,
+    ///Sent as a bearer token, local servers usually don't need one
+    pub api_key: Option<String>,
+    ///Instructions describing how the bot should reply
+    pub system_prompt: Option<String>,
+    ///Longest reply the model can generate
+    #[serde(default = "default_max_tokens")]
+    pub max_tokens: u32,
+    ///How many seconds to wait for the model before falling back to the words
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    ///Send the posts the mention replied to along with it
+    #[serde(default)]
+    pub include_thread: bool,
+}
+
+impl std::fmt::Debug for LlmOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        //Destructured so new fields can't be forgotten here
+        let Self {
+            endpoint,
+            model,
+            api_key,
+            system_prompt,
+            max_tokens,
+            timeout,
+            include_thread,
+        } = self;
+
+        f.debug_struct("LlmOptions")
+            .field("endpoint", endpoint)
+            .field("model", model)
+            .field("api_key", &api_key.as_ref().map(|_| "<redacted>"))
+            .field("system_prompt", system_prompt)
+            .field("max_tokens", max_tokens)
+            .field("timeout", timeout)
+            .field("include_thread", include_thread)
+            .finish()
+    }
+}
+
+fn default_max_tokens() -> u32 {
+    100
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChatMessage {
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<ChatMessage>,
+    pub max_tokens: u32,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatChoice {
+    pub message: ChatMessage,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatResponse {
+    pub choices: Vec<ChatChoice>,
+}
+
+///Client for the `/v1/chat/completions` endpoint
+#[derive(Debug)]
+pub struct Llm {
+    options: LlmOptions,
+    client: reqwest::Client,
+}
+
+impl Llm {
+    pub fn new(options: LlmOptions) -> Result<Self, String> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(options.timeout))
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self { options, client })
+    }
+
+    pub fn include_thread(&self) -> bool {
+        self.options.include_thread
+    }
+
+    ///Asks the model for a reply to the post, the reply is empty if the model didn't give one
+    pub async fn complete(&self, context: &Context) -> Result<String, ClientError> {
+        let mut messages = Vec::new();
+
+        if let Some(prompt) = &self.options.system_prompt {
+            messages.push(ChatMessage {
+                role: "system".into(),
+                content: prompt.clone(),
+            });
+        }
+
+        if self.options.include_thread {
+            for i in &context.thread {
+                messages.push(ChatMessage {
+                    role: "user".into(),
+                    content: format!("{}: {}", i.author, i.text),
+                });
+            }
+        }
+
+        messages.push(ChatMessage {
+            role: "user".into(),
+            content: format!(
+                "{}: {}",
+                context.author,
+                context.text.as_deref().unwrap_or_default()
+            ),
+        });
+
+        let request = ChatRequest {
+            model: self.options.model.clone(),
+            messages,
+            max_tokens: self.options.max_tokens,
+        };
+
+        let mut request = self
+            .client
+            .post(self.options.endpoint.trim_end_matches('/').to_string() + "/v1/chat/completions")
+            .json(&request);
+
+        if let Some(key) = &self.options.api_key {
+            request = request.bearer_auth(key);
+        }
+
+        let response: ChatResponse = parse_response(request.send().await?).await?;
+
+        Ok(response
+            .choices
+            .into_iter()
+            .next()
+            .map(|i| i.message.content.trim().to_string())
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::responses::ThreadPost;
+
+    ///Answers one request with `body` and returns the request it got
+    async fn serve_once(listener: TcpListener, status: &str, body: &str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+
+        //Reads the headers, then as much of the body as they announce
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+
+            let text = String::from_utf8_lossy(&request);
+
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .find_map(|i| {
+                        i.to_lowercase()
+                            .strip_prefix("content-length:")
+                            .map(|i| i.trim().parse::<usize>().unwrap())
+                    })
+                    .unwrap_or(0);
+
+                if request.len() >= end + 4 + length {
+                    break;
+                }
+            }
+        }
+
+        let response = format!(
+            "HTTP/1.1 {status}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+
+        String::from_utf8(request).unwrap()
+    }
+
+    async fn llm(options: impl FnOnce(&mut LlmOptions)) -> (Llm, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+        let mut llm_options = LlmOptions {
+            endpoint: format!("http://{}/", listener.local_addr().unwrap()),
+            model: "test-model".into(),
+            api_key: None,
+            system_prompt: None,
+            max_tokens: 50,
+            timeout: 5,
+            include_thread: false,
+        };
+        options(&mut llm_options);
+
+        (Llm::new(llm_options).unwrap(), listener)
+    }
+
+    fn context() -> Context {
+        Context {
+            text: Some("@grok is this true?".into()),
+            author: "@alice@example.com".into(),
+            thread: vec![ThreadPost {
+                author: "@bob@example.com".into(),
+                text: "the sky is green".into(),
+            }],
+            parent: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn completes_against_a_server() {
+        let (llm, listener) = llm(|o| {
+            o.api_key = Some("secret".into());
+            o.system_prompt = Some("be brief".into());
+            o.include_thread = true;
+        })
+        .await;
+
+        let server = tokio::spawn(serve_once(
+            listener,
+            "200 OK",
+            r#"{"choices": [{"message": {"role": "assistant", "content": "  yes  "}}]}"#,
+        ));
+
+        assert_eq!(llm.complete(&context()).await.unwrap(), "yes");
+
+        let request = server.await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+
+        assert!(head.starts_with("POST /v1/chat/completions HTTP/1.1"));
+        assert!(head.to_lowercase().contains("authorization: bearer secret"));
+
+        let body: serde_json::Value = serde_json::from_str(body).unwrap();
+        assert_eq!(body["model"], "test-model");
+        assert_eq!(body["max_tokens"], 50);
+        assert_eq!(
+            body["messages"],
+            serde_json::json!([
+                {"role": "system", "content": "be brief"},
+                {"role": "user", "content": "@bob@example.com: the sky is green"},
+                {"role": "user", "content": "@alice@example.com: @grok is this true?"},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn empty_choices_give_an_empty_reply() {
+        let (llm, listener) = llm(|_| {}).await;
+        let server = tokio::spawn(serve_once(listener, "200 OK", r#"{"choices": []}"#));
+
+        assert_eq!(llm.complete(&context()).await.unwrap(), "");
+
+        let request = server.await.unwrap();
+        assert!(!request.to_lowercase().contains("authorization"));
+        //The thread is only sent if it's enabled
+        assert!(!request.contains("the sky is green"));
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_returned() {
+        let (llm, listener) = llm(|_| {}).await;
+        let server = tokio::spawn(serve_once(
+            listener,
+            "500 Internal Server Error",
+            r#"{"error": "model not loaded"}"#,
+        ));
+
+        assert!(llm.complete(&context()).await.is_err());
+        server.await.unwrap();
+    }
+}
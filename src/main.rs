@@ -1,267 +1,994 @@
+use std::io::{Read, Write};
 use std::time::Duration;
 
-use rand::Rng;
-use regex::Regex;
-use serde::{Deserialize, Serialize};
-
-use crate::mastodon_client::{Client, NotificationType, Post};
-
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+
+use crate::cli::{Cli, Command, RunOptions};
+use crate::config::Config;
+use crate::error::ClientError;
+use crate::fediverse::{Account, Backend, FediverseClient, Mention, MentionEvent, Payload};
+use crate::nodeinfo::{Instance, Quirks};
+use crate::responses::{Context, Rules, ThreadPost, generate_with};
+use crate::state::State;
+
+pub mod cli;
+pub mod config;
+pub mod error;
+pub mod fediverse;
+pub mod llm;
+pub mod markov;
 pub mod mastodon_client;
 pub mod misskey_client;
+pub mod nodeinfo;
+pub mod pleroma_client;
+pub mod request;
+pub mod responses;
+pub mod simulate;
+pub mod state;
+pub mod streaming;
+
+async fn reply_to_mention(
+    config: &Config,
+    rules: &Rules,
+    connection: &Connection,
+    mention: &Mention,
+    options: &RunOptions,
+) -> Result<(), ClientError> {
+    //Skip pings made by bots
+    if mention.author.bot {
+        return Ok(());
+    }
 
-fn generate_response(config: &Config, post_text: &Option<String>) -> (String, u32) {
-    let mut rng = rand::rng();
-
-    for (ind, r) in config.responses.iter().enumerate() {
-        //First check if this is not the last dictionary, and if it is use it regardless of any
-        //other checks
-        if !(ind == config.responses.len() - 1) {
-            //First try to get random
-            if rng.random_range(0..100) > r.chance {
-                //Randomness check did not succeed<F
-                continue;
-            }
+    let tag = config.tag();
+    let Connection { client, me, .. } = connection;
 
-            //Check if it matches the regex
-            if let Some(regex) = &r.regex {
-                if let Some(text) = post_text {
-                    let regex = Regex::new(&regex).unwrap();
+    if options.verbose {
+        println!(
+            "{tag}Mention {} from {}: {}",
+            mention.status_id,
+            mention.author.handle(),
+            mention.text.as_deref().unwrap_or_default()
+        );
+    }
 
-                    if !regex.is_match(text) {
-                        continue;
-                    }
-                } else {
-                    //There's no post text so it will never match the regex
-                    continue;
-                }
+    let mut context = Context {
+        text: mention.text.clone(),
+        author: format!("@{}", mention.author.handle()),
+        ..Default::default()
+    };
+
+    if rules.needs_thread() {
+        match client.fetch_thread(mention).await {
+            Ok(thread) => {
+                context.thread = thread
+                    .into_iter()
+                    .filter_map(|i| {
+                        Some(ThreadPost {
+                            author: format!("@{}", i.author.handle()),
+                            text: i.text?,
+                        })
+                    })
+                    .collect()
             }
+            Err(e) => println!("{tag}Failed to fetch the thread: {e}"),
         }
+    }
 
-        let num_words = rng.random_range(r.min_words..r.max_words);
+    if rules.needs_parent()
+        && let Some(id) = &mention.in_reply_to_id
+    {
+        match client.fetch_post(id.clone()).await {
+            Ok(post) => context.parent = Some(post),
+            Err(e) => println!("{tag}Failed to fetch the parent post: {e}"),
+        }
+    }
+
+    //The same generator is used when regenerating, a fresh one could be seeded the same way
+    let mut rng = rules.rng.rng(&mention.status_id);
 
-        let mut o = String::new();
+    let (meow, react) = loop {
+        let mut trace = Vec::new();
+        let ind = rules.pick_traced(&context, &mut rng, &mut trace);
+        let meow = generate_with(rules, &rules.rules[ind], &context, &mut rng).await;
+        let react = rules.rules[ind].react;
 
-        for _ in 0..num_words {
-            o += &r.words[rng.random_range(0..r.words.len())];
-            o += " ";
+        if options.verbose {
+            trace.iter().for_each(|i| println!("{tag}  {i}"));
         }
 
-        return (o.trim_end().into(), num_words);
+        //i sure love sharkey
+        if react
+            || !(connection.quirks().lone_emoji_replies
+                && meow.1 == 1
+                && meow.0.starts_with(':')
+                && meow.0.ends_with(':'))
+        {
+            break (meow, react);
+        }
+
+        println!("{tag}regenerating the meow");
+    };
+
+    if react {
+        //Words often have spacing around the emoji
+        let reaction = meow
+            .0
+            .trim_matches(|c: char| c.is_whitespace() || c == '\u{200c}')
+            .to_string();
+
+        if options.dry_run {
+            write_dry_run(mention, client.react_payload(mention, reaction), options);
+            return Ok(());
+        }
+
+        if options.verbose {
+            println!("{tag}Reacting to {} with {reaction}", mention.status_id);
+        }
+
+        return client.react(mention, reaction).await;
     }
 
-    unreachable!()
+    //Chats only have the two accounts in them, so there's nobody to ping
+    if mention.chat_id.is_some() {
+        return send_reply(config, client.as_ref(), mention, meow.0, options).await;
+    }
+
+    let mut pings = String::new();
+
+    for i in &mention.mentions {
+        if i.id == me.id {
+            println!("{tag}Skipped self");
+            continue;
+        }
+
+        pings += &format!("@{} ", i.handle());
+    }
+
+    let text = format!("@{} {pings}{}", mention.author.handle(), meow.0);
+
+    send_reply(config, client.as_ref(), mention, text, options).await
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Response {
-    ///% chance that the bot will reply  with the following words
-    chance: u32,
-    ///Checks the post against this regex and uses this dictionary if it matches
-    regex: Option<String>,
-    ///Minimum number of words the bot will respond with
-    min_words: u32,
-    ///Maximum number of words the bot will respond with
-    max_words: u32,
-    ///Wether the dictionary contains any emoji
-    contains_emoji: bool,
-    ///Dictionary of words the bot will reply with
-    ///
-    ///Note:
-    ///
-    ///Order matters, the program will try to generate responses in order, so chance percentages
-    ///are a bit skewed. For example if the first response has a chance of 10%, then 2nd has 15%
-    ///and 3rd has 20%, the first one will have a chance of 10% then the 2nd one will have 15% of
-    ///100 - 10%, and if both fail then the last one will be used.
-    words: Vec<String>,
+///Replies to the mention, or writes the reply out on dry runs
+async fn send_reply(
+    config: &Config,
+    client: &dyn FediverseClient,
+    mention: &Mention,
+    text: String,
+    options: &RunOptions,
+) -> Result<(), ClientError> {
+    if options.dry_run {
+        write_dry_run(mention, client.reply_payload(mention, text), options);
+        return Ok(());
+    }
+
+    if options.verbose {
+        println!("{}Replying to {}: {text}", config.tag(), mention.status_id);
+    }
+
+    client.reply(mention, text).await
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Config {
-    ///Instance URL
-    instance: String,
-    ///Api access token
-    token: String,
-    ///How much time to wait before checking notifications again
-    polling_interval: u64,
-    ///Things the bot can respond with
-    responses: Vec<Response>,
+///Prints the reply that would have been posted, or appends it to the dry run output file
+fn write_dry_run(mention: &Mention, payload: Payload, options: &RunOptions) {
+    let line = serde_json::json!({
+        "notification_id": mention.id,
+        "status_id": mention.status_id,
+        "endpoint": payload.endpoint,
+        "body": payload.body,
+    })
+    .to_string();
+
+    let Some(path) = &options.dry_run_output else {
+        println!("{line}");
+        return;
+    };
+
+    let written = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut f| writeln!(f, "{line}"));
+
+    if let Err(e) = written {
+        println!("Failed to write to {path}: {e}");
+    }
 }
 
-fn generate_default_config() -> Config {
-    Config {
-        instance: "https://test.com".into(),
-        token: "API_TOKEN".into(),
-        polling_interval: 10,
-        responses: vec![
-            Response {
-                regex: Some(r"(@.*)*is this true\?".into()),
-                chance: 100,
-                min_words: 1,
-                max_words: 2,
-                contains_emoji: true,
-                words: vec![
-                    "meow !!!".into(),
-                    " ‌:neocat_sign_yes:".into(),
-                    " ‌:neocat_sign_no:".into(),
-                    "nyaa ?".into(),
-                    " ‌:neocat_confused:".into(),
-                    " ‌:neocat_glare:".into(),
-                    " ‌:neocat_woozy:".into(),
-                    " ‌:neocat_googly_woozy:".into(),
-                ],
-            },
-            Response {
-                regex: None,
-                chance: 10,
-                min_words: 1,
-                max_words: 10,
-                contains_emoji: true,
-                words: vec![
-                    "waf".into(),
-                    "arrf".into(),
-                    "awrfaf".into(),
-                    ":neofox_floof:".into(),
-                    ":fluffy_tail:".into(),
-                ],
-            },
-            Response {
-                regex: None,
-                chance: 100,
-                min_words: 1,
-                max_words: 10,
-                contains_emoji: false,
-                words: vec![
-                    "meow".into(),
-                    "mew".into(),
-                    "nyaaa".into(),
-                    ":3".into(),
-                    "mrrp".into(),
-                    "mrmeow".into(),
-                    ":neocat_floof:".into(),
-                    ":neocat_flop:".into(),
-                ],
-            },
-        ],
+///Replies to the mention unless it was already processed and saves the new state.
+///
+///Returns an error if replying failed but could succeed later, the mention is left unprocessed
+///then
+async fn process_mention(
+    config: &Config,
+    rules: &Rules,
+    connection: &Connection,
+    state: &mut State,
+    mention: Mention,
+    options: &RunOptions,
+) -> Result<(), ClientError> {
+    if state.is_processed(&mention.status_id) {
+        println!("{}Already replied to {}", config.tag(), mention.status_id);
+    } else {
+        match reply_to_mention(config, rules, connection, &mention, options).await {
+            Ok(()) => {}
+            Err(e) if e.is_retryable() => return Err(e),
+            //Retrying won't help, so don't get stuck on it
+            Err(e) => println!(
+                "{}Failed to reply to {}: {e}",
+                config.tag(),
+                mention.status_id
+            ),
+        }
+    }
+
+    state.mark_processed(mention.id, mention.status_id);
+
+    //The cursor still moves in memory, so the same mentions aren't printed over and over
+    if options.dry_run {
+        return Ok(());
+    }
+
+    if let Err(e) = state.save(&config.state_file) {
+        println!("{}Failed to save the state: {e}", config.tag());
     }
+
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() {
-    tokio::time::sleep(Duration::from_secs(10)).await;
+///Processes all the mentions that arrived since the last processed one, stopping early on
+///shutdown. Returns how many were processed
+async fn poll_mentions(
+    config: &Config,
+    rules: &Rules,
+    connection: &Connection,
+    state: &mut State,
+    options: &RunOptions,
+    shutdown: &CancellationToken,
+) -> Result<usize, ClientError> {
+    let tag = config.tag();
+    let client = &connection.client;
+
+    println!("{tag}Checking notifications");
+    //Get notifications
+    let mentions = client
+        .fetch_mentions(state.last_notification_id.clone())
+        .await?;
+
+    if mentions.is_empty() {
+        return Ok(0);
+    }
 
-    let args = std::env::args().collect::<Vec<_>>();
+    println!("{tag}Replying");
 
-    if args.len() > 1 {
-        match args[1].as_str() {
-            "-c" => {
-                let c = generate_default_config();
-                let s = toml::to_string(&c).unwrap();
+    let total = mentions.len();
+    let mut processed = 0;
 
-                std::fs::write("./config.toml", s).unwrap();
-                return;
+    //Oldest first, so the cursor only ever moves forward
+    for mention in mentions.into_iter().rev() {
+        //The rest stay after the cursor and get picked up on the next start
+        if shutdown.is_cancelled() {
+            println!("{tag}Leaving {} mentions for later", total - processed);
+            return Ok(processed);
+        }
+
+        process_mention(config, rules, connection, state, mention, options).await?;
+        processed += 1;
+    }
+
+    if options.dry_run {
+        return Ok(processed);
+    }
+
+    //Marking notifications as read is only housekeeping, the cursor already prevents duplicates
+    if client.rate_limit().is_low() {
+        println!("{tag}Running low on requests, not marking notifications as read");
+        return Ok(processed);
+    }
+
+    if let Some(id) = state.last_notification_id.clone() {
+        println!("{tag}Marking notifications as read");
+        client.mark_read(id).await?;
+    }
+
+    Ok(processed)
+}
+
+///Waits for SIGINT or SIGTERM
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut terminate = signal(SignalKind::terminate()).unwrap();
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap();
+}
+
+///Returns a token that's cancelled on the first SIGINT or SIGTERM, a second one exits right away
+fn listen_for_shutdown() -> CancellationToken {
+    let token = CancellationToken::new();
+    let cancel = token.clone();
+
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        println!("Shutting down after the current reply, signal again to stop immediately");
+        cancel.cancel();
+
+        wait_for_signal().await;
+        println!("Stopping immediately");
+        std::process::exit(130);
+    });
+
+    token
+}
+
+///Loads the accounts of the config, only the one picked with `--account` if there is one.
+///Exits if the config is invalid or the account doesn't exist
+fn load_config(path: &str, options: &RunOptions) -> Vec<(Config, Rules)> {
+    let accounts = match Config::load(path) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Invalid config:\n{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let Some(name) = &options.account else {
+        return accounts;
+    };
+
+    let picked = accounts
+        .into_iter()
+        .filter(|(c, _)| &c.name == name)
+        .collect::<Vec<_>>();
+
+    if picked.is_empty() {
+        println!("{path} has no account called `{name}`");
+        std::process::exit(1);
+    }
+
+    picked
+}
+
+///Loads the config for commands that only work with one account
+fn load_single_account(path: &str, options: &RunOptions) -> (Config, Rules) {
+    let mut accounts = load_config(path, options);
+
+    if accounts.len() > 1 {
+        println!("{path} has several accounts, pick one with --account");
+        std::process::exit(1);
+    }
+
+    accounts.remove(0)
+}
+
+///The api to use, detected from the software of the instance if the config leaves it to that
+fn pick_backend(config: &Config, instance: Option<&Instance>) -> Backend {
+    match config.backend {
+        Backend::auto => instance.map_or(Backend::mastodon, |i| i.platform.backend()),
+        b => b,
+    }
+}
+
+fn connect(
+    config: &Config,
+    backend: Backend,
+    instance: Option<&Instance>,
+) -> Box<dyn FediverseClient> {
+    match backend {
+        //Already resolved by pick_backend
+        Backend::auto | Backend::mastodon => Box::new(mastodon_client::Client::new(
+            config.token.clone(),
+            config.instance.clone(),
+        )),
+        Backend::misskey => Box::new(misskey_client::Client::new(
+            config.token.clone(),
+            config.instance.clone(),
+        )),
+        Backend::pleroma => Box::new(pleroma_client::Client::new(
+            config.token.clone(),
+            config.instance.clone(),
+            //Assume the instance has them if it couldn't be asked
+            instance.is_none_or(|i| i.has_feature("pleroma_emoji_reactions")),
+        )),
+    }
+}
+
+///Exit code when the token is rejected
+const EXIT_UNAUTHORIZED: i32 = 2;
+///Exit code when the instance couldn't be reached before the startup deadline
+const EXIT_UNREACHABLE: i32 = 3;
+
+const MIN_STARTUP_DELAY: Duration = Duration::from_secs(1);
+const MAX_STARTUP_DELAY: Duration = Duration::from_secs(60);
+
+///A client that's logged in, with what's known about the instance
+struct Connection {
+    client: Box<dyn FediverseClient>,
+    backend: Backend,
+    ///The account the client is logged in as
+    me: Account,
+    ///`None` if the instance doesn't have nodeinfo
+    instance: Option<Instance>,
+}
+
+impl Connection {
+    fn quirks(&self) -> Quirks {
+        self.instance
+            .as_ref()
+            .map_or(Quirks::UNKNOWN, Instance::quirks)
+    }
+
+    ///Like `sharkey 2025.4.3 through the misskey api`
+    fn describe(&self) -> String {
+        let software = match &self.instance {
+            Some(i) => format!("{} {}", i.software, i.version),
+            None => "unknown software".into(),
+        };
+
+        format!("{software} through the {:?} api", self.backend)
+    }
+}
+
+///Waits for the instance to answer, detects its software and logs in, retrying with an
+///exponential backoff until `timeout` passes. Returns the exit code if the token is rejected or
+///the deadline passes
+async fn wait_until_ready(config: &Config, timeout: Duration) -> Result<Connection, i32> {
+    let tag = config.tag();
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut delay = MIN_STARTUP_DELAY;
+    let mut attempt = 1;
+
+    loop {
+        //Instances without nodeinfo are fine, only a failure to reach them counts
+        let instance = match nodeinfo::detect(&config.instance).await {
+            Ok(i) => Ok(Some(i)),
+            Err(e) if e.is_retryable() => Err(e),
+            Err(_) => Ok(None),
+        };
+
+        let error = match instance {
+            Err(e) => e,
+            Ok(instance) => {
+                let backend = pick_backend(config, instance.as_ref());
+                let client = connect(config, backend, instance.as_ref());
+
+                match client.whoami().await {
+                    Ok(me) => {
+                        return Ok(Connection {
+                            client,
+                            backend,
+                            me,
+                            instance,
+                        });
+                    }
+                    Err(e @ ClientError::Unauthorized { .. }) => {
+                        println!("{tag}The token was rejected by {}: {e}", config.instance);
+                        return Err(EXIT_UNAUTHORIZED);
+                    }
+                    Err(e) if e.is_retryable() => e,
+                    Err(e) => {
+                        println!("{tag}Failed to connect to {}: {e}", config.instance);
+                        return Err(1);
+                    }
+                }
             }
-            _ => {
-                println!("Usage: grok [-h|-c]");
-                println!("\t-h displays this menu");
-                println!("\t-c creates default config file");
-                return;
+        };
+
+        let now = tokio::time::Instant::now();
+
+        if now + delay > deadline {
+            println!(
+                "{tag}Failed to reach {} after {attempt} attempts: {error}",
+                config.instance
+            );
+            return Err(EXIT_UNREACHABLE);
+        }
+
+        println!(
+            "{tag}{} isn't ready ({error}), retrying in {}s (attempt {attempt})",
+            config.instance,
+            delay.as_secs()
+        );
+
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_STARTUP_DELAY);
+        attempt += 1;
+    }
+}
+
+///How often the config file is checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+///Returns a receiver that gets a message whenever the config file changes or on SIGHUP
+fn listen_for_reload(path: String) -> mpsc::Receiver<()> {
+    let (tx, rx) = mpsc::channel(1);
+
+    tokio::spawn(async move {
+        let modified = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut last = modified(&path);
+
+        #[cfg(unix)]
+        let mut hangup =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).unwrap();
+
+        while !tx.is_closed() {
+            #[cfg(unix)]
+            let signalled = tokio::select! {
+                _ = hangup.recv() => true,
+                _ = tokio::time::sleep(RELOAD_CHECK_INTERVAL) => false,
+            };
+
+            #[cfg(not(unix))]
+            let signalled = {
+                tokio::time::sleep(RELOAD_CHECK_INTERVAL).await;
+                false
+            };
+
+            let current = modified(&path);
+
+            if signalled || current != last {
+                last = current;
+                //A reload is already pending if it's full
+                let _ = tx.try_send(());
             }
         }
+    });
+
+    rx
+}
+
+///Re-reads the config of the account, keeping the old one if the new one is invalid, the account
+///is gone or its credentials don't work. The client is only replaced if the instance or the token
+///changed, the state is loaded again if it belongs to another instance or file now. Returns whether
+///the mentions have to be received differently now
+async fn reload_config(
+    path: &str,
+    config: &mut Config,
+    rules: &mut Rules,
+    connection: &mut Connection,
+    state: &mut State,
+) -> bool {
+    let tag = config.tag();
+
+    let accounts = match Config::load(path) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("{tag}Invalid config, keeping the old one:\n{e}");
+            return false;
+        }
+    };
+
+    //Accounts are told apart by name, adding or removing them needs a restart
+    let Some((new_config, new_rules)) = accounts.into_iter().find(|(c, _)| c.name == config.name)
+    else {
+        println!("{tag}The account is gone from {path}, keeping the old config");
+        return false;
+    };
+
+    let reconnect = new_config.instance != config.instance
+        || new_config.token != config.token
+        || new_config.backend != config.backend;
+
+    //Ids of one instance or api mean nothing to another
+    let new_state = if new_config.instance != config.instance
+        || new_config.backend != config.backend
+        || new_config.state_file != config.state_file
+    {
+        match State::load(&new_config.state_file) {
+            Ok(s) => Some(s),
+            Err(e) => {
+                println!("{tag}Failed to load {e}, keeping the old config");
+                return false;
+            }
+        }
+    } else {
+        None
+    };
+
+    if reconnect {
+        match wait_until_ready(&new_config, Duration::ZERO).await {
+            Ok(c) => {
+                println!("{tag}Connected as {} to {}", c.me.handle(), c.describe());
+                *connection = c;
+            }
+            Err(_) => {
+                println!("{tag}Failed to connect with the new config, keeping the old one");
+                return false;
+            }
+        }
+    }
+
+    let restart = reconnect || new_state.is_some() || new_config.streaming != config.streaming;
+
+    if let Some(s) = new_state {
+        *state = s;
     }
 
-    let str = std::fs::read_to_string("./config.toml").unwrap();
+    *config = new_config;
+    *rules = new_rules;
 
-    let config: Config = toml::from_str(&str).unwrap();
+    println!("{tag}Reloaded {path}");
+    restart
+}
+
+///Runs every account of the config side by side until the process is stopped, or once with
+///`--once`. Exits with the code of the first failure if no account could run
+async fn run(config_path: &str, options: &RunOptions) {
+    let accounts = load_config(config_path, options);
     println!("Parsed confg file");
 
-    let misskey_client = misskey_client::Client::new(config.token.clone(), config.instance.clone());
-    let masto_client = Client::new(config.token.clone(), config.instance.clone());
+    if options.dry_run {
+        println!("Dry run, nothing will be posted");
+    }
 
-    //Test the clients
-    let _ = masto_client.me().await.unwrap();
-    let _ = misskey_client.me().await.unwrap();
+    let shutdown = listen_for_shutdown();
+    let mut tasks = tokio::task::JoinSet::new();
+    //The other accounts keep running, so there's no reason to give up on an unreachable one
+    let keep_trying = accounts.len() > 1 && !options.once;
+
+    for (config, rules) in accounts {
+        tasks.spawn(run_account(
+            config_path.into(),
+            config,
+            rules,
+            options.clone(),
+            keep_trying,
+            shutdown.clone(),
+        ));
+    }
 
-    println!("Connected to misskey and mastodon");
+    let mut ran = false;
+    let mut code = None;
 
-    loop {
-        println!("Checking notifications");
-        //Get notifications
-        let notifications = masto_client.get_notifications().await;
-        //If got some notifications immediately flush them
-        if !notifications.is_empty() {
-            println!("Clearing notifications");
-            misskey_client.flush_notifications().await.unwrap();
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Ok(())) => ran = true,
+            Ok(Err(c)) => _ = code.get_or_insert(c),
+            Err(e) => {
+                println!("An account stopped unexpectedly: {e}");
+                _ = code.get_or_insert(1);
+            }
+        }
+    }
+
+    //One broken account shouldn't take down the others, only exit if none of them worked
+    if let Some(code) = code
+        && (!ran || options.once)
+    {
+        std::process::exit(code);
+    }
+}
+
+///Replies to the mentions of one account until `shutdown` is cancelled, or once with `--once`.
+///With `keep_trying` an instance that's still unreachable after `startup_timeout` is retried until
+///it answers. Returns the exit code if the account can't log in, couldn't reach the instance or,
+///with `--once`, couldn't check the mentions
+async fn run_account(
+    config_path: String,
+    mut config: Config,
+    mut rules: Rules,
+    options: RunOptions,
+    keep_trying: bool,
+    shutdown: CancellationToken,
+) -> Result<(), i32> {
+    let options = &options;
+
+    //Waiting for the instance can take minutes, a shutdown shouldn't
+    let mut connection = loop {
+        let ready = tokio::select! {
+            c = wait_until_ready(&config, Duration::from_secs(config.startup_timeout)) => c,
+            _ = shutdown.cancelled() => {
+                println!("{}Stopped before connecting", config.tag());
+                return Ok(());
+            }
+        };
+
+        match ready {
+            Ok(c) => break c,
+            Err(EXIT_UNREACHABLE) if keep_trying => {
+                println!("{}Still trying to reach {}", config.tag(), config.instance)
+            }
+            Err(code) => return Err(code),
         }
+    };
+
+    println!(
+        "{}Connected as {} to {}",
+        config.tag(),
+        connection.me.handle(),
+        connection.describe()
+    );
+
+    if options.verbose
+        && let Some(i) = &connection.instance
+        && !i.features.is_empty()
+    {
+        println!(
+            "{}Instance features: {}",
+            config.tag(),
+            i.features.join(", ")
+        );
+    }
 
-        let names = notifications
-            .into_iter()
-            .filter(|i| i.r#type == NotificationType::mention)
-            .map(|i| {
-                let status = i.status.unwrap();
-                (
-                    i.account.acct,
-                    status.text,
-                    status.id,
-                    status.visibility,
-                    status.mentions,
-                    status.account.bot,
-                )
-            })
-            .collect::<Vec<_>>();
-
-        println!("Replying");
-
-        for (username, text, status_id, visibility, mentions, is_bot) in names {
-            //Skip pings made by bots
-            if is_bot {
-                continue;
+    let mut state = match State::load(&config.state_file) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("{}Failed to load {}: {e}", config.tag(), config.state_file);
+            return Err(1);
+        }
+    };
+
+    let mut reload = listen_for_reload(config_path.clone());
+    //Mentions processed since the start, for the summary on shutdown
+    let mut processed = 0;
+
+    macro_rules! poll {
+        () => {
+            match poll_mentions(&config, &rules, &connection, &mut state, options, &shutdown).await
+            {
+                Ok(n) => {
+                    processed += n;
+                    true
+                }
+                Err(e) => {
+                    println!("{}Failed to check notifications: {e}", config.tag());
+                    false
+                }
             }
+        };
+    }
 
-            let mut meow = generate_response(&config, &text);
+    if options.once && !poll!() {
+        return Err(1);
+    }
+
+    //Restarted whenever a reload changes how the bot connects
+    while !options.once && !shutdown.is_cancelled() {
+        let mut mentions = None;
+        //Polling until the mentions the stream missed or couldn't reply to are handled
+        let mut catching_up = false;
+
+        if config.streaming {
+            //The mastodon api of misskey forks can't stream
+            if connection.backend != Backend::mastodon || connection.quirks().mastodon_streaming {
+                mentions = connection.client.stream_mentions();
+            }
+
+            if mentions.is_none() {
+                println!(
+                    "{}Streaming is not supported by the backend, polling instead",
+                    config.tag()
+                );
+            } else {
+                println!("{}Streaming mentions", config.tag());
+            }
+        }
 
-            //i sure love sharkey
-            loop {
-                if meow.1 == 1 {
-                    if meow.0.chars().next().unwrap() == ':'
-                        && meow.0.chars().last().unwrap() == ':'
-                    {
-                        println!("regenerating the meow");
-                        meow = generate_response(&config, &text);
+        loop {
+            if let Some(stream) = &mut mentions {
+                let event = tokio::select! {
+                    e = stream.next() => e,
+                    _ = shutdown.cancelled() => break,
+                    _ = reload.recv() => {
+                        if reload_config(&config_path, &mut config, &mut rules, &mut connection, &mut state).await {
+                            break;
+                        }
                         continue;
                     }
+                };
+
+                let mention = match event {
+                    Some(MentionEvent::Mention(m)) => *m,
+                    //The stream only has what arrived after it connected, the rest is after the
+                    //cursor
+                    Some(MentionEvent::Connected) => {
+                        if !poll!() {
+                            println!("{}Polling until it works", config.tag());
+                            mentions = None;
+                            catching_up = true;
+                        }
+                        continue;
+                    }
+                    Some(MentionEvent::Missed(e)) => {
+                        println!(
+                            "{}Failed to read a streamed mention, polling until it works: {e}",
+                            config.tag()
+                        );
+                        mentions = None;
+                        catching_up = true;
+                        continue;
+                    }
+                    None => {
+                        println!("{}Streaming stopped, polling instead", config.tag());
+                        mentions = None;
+                        continue;
+                    }
+                };
+
+                let id = mention.status_id.clone();
+
+                if let Err(e) =
+                    process_mention(&config, &rules, &connection, &mut state, mention, options)
+                        .await
+                {
+                    //The stream won't send it again, but polling gets it from the cursor
+                    println!(
+                        "{}Failed to reply to {id}, polling until it works: {e}",
+                        config.tag()
+                    );
+                    mentions = None;
+                    catching_up = true;
+                    continue;
+                }
+
+                processed += 1;
+
+                if !options.dry_run
+                    && !connection.client.rate_limit().is_low()
+                    && let Some(id) = state.last_notification_id.clone()
+                    && let Err(e) = connection.client.mark_read(id).await
+                {
+                    println!("{}Failed to mark notifications as read: {e}", config.tag());
+                }
+            } else {
+                if poll!() && catching_up {
+                    println!("{}Caught up, streaming again", config.tag());
+                    break;
+                }
+
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(config.polling_interval)) => {}
+                    _ = shutdown.cancelled() => break,
+                    _ = reload.recv() => {
+                        if reload_config(&config_path, &mut config, &mut rules, &mut connection, &mut state).await {
+                            break;
+                        }
+                    }
                 }
-                break;
             }
+        }
+    }
 
-            let mut pings = String::new();
+    //Every processed mention already saved it, this only catches a failed save
+    if !options.dry_run
+        && let Err(e) = state.save(&config.state_file)
+    {
+        println!("{}Failed to save the state: {e}", config.tag());
+    }
 
-            for i in mentions {
-                //Probably don't need this
-                if i.username == "grok" {
-                    println!("Skipped self");
-                    continue;
+    println!(
+        "{}Stopped after processing {processed} mentions, last notification {}",
+        config.tag(),
+        state.last_notification_id.as_deref().unwrap_or("none")
+    );
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    //A missing .env is fine, the variables can be set any other way
+    dotenv::dotenv().ok();
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+
+    let cli = match Cli::parse(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("{e}\n\n{}", cli::USAGE);
+            std::process::exit(1);
+        }
+    };
+
+    match cli.command {
+        Command::Run => run(&cli.config, &cli.options).await,
+        Command::Init { force } => {
+            if !force && std::path::Path::new(&cli.config).exists() {
+                println!("{} already exists, use --force to overwrite it", cli.config);
+                std::process::exit(1);
+            }
+
+            let c = config::generate_default_config();
+            let s = toml::to_string(&c).unwrap();
+
+            if let Err(e) = std::fs::write(&cli.config, s) {
+                println!("Failed to write {}: {e}", cli.config);
+                std::process::exit(1);
+            }
+
+            println!("Created {}", cli.config);
+        }
+        Command::Check => {
+            let accounts = load_config(&cli.config, &cli.options);
+            println!("{} is valid", cli.config);
+
+            let mut code = None;
+
+            for (config, _) in accounts {
+                match wait_until_ready(&config, Duration::ZERO).await {
+                    Ok(c) => println!(
+                        "{}Logged in to {} as {}, {}",
+                        config.tag(),
+                        config.instance,
+                        c.me.handle(),
+                        c.describe()
+                    ),
+                    Err(c) => _ = code.get_or_insert(c),
                 }
+            }
 
-                // https://lunar.place/@luna
-                let binding = i.url.split("https://").collect::<Vec<_>>();
+            if let Some(code) = code {
+                std::process::exit(code);
+            }
+        }
+        Command::Simulate(options) => {
+            if options.help {
+                println!("{}", simulate::USAGE);
+                return;
+            }
 
-                //lunar.place/@luna
-                let instance = binding.last().unwrap().split("/").next().unwrap();
+            let (_, rules) = load_single_account(&cli.config, &cli.options);
 
-                pings += &format!("@{}@{instance} ", i.username);
+            if let Err(e) = simulate::run(&rules, &options).await {
+                println!("{e}");
+                std::process::exit(1);
             }
+        }
+        Command::Whoami => {
+            let mut code = None;
 
-            //Check if the meow is just a single emoji
-            let post = Post {
-                status: format!("@{username} {pings}{}", meow.0),
-                in_reply_to_id: Some(status_id),
-                visibility: Some(visibility),
-                ..Default::default()
-            };
+            for (config, _) in load_config(&cli.config, &cli.options) {
+                match wait_until_ready(&config, Duration::ZERO).await {
+                    Ok(c) => println!("{}{} ({})", config.tag(), c.me.handle(), c.me.id),
+                    Err(c) => _ = code.get_or_insert(c),
+                }
+            }
 
-            masto_client.create_post(post).await.unwrap();
+            if let Some(code) = code {
+                std::process::exit(code);
+            }
         }
+        Command::Post { text } => {
+            let text = match text {
+                Some(t) => t,
+                None => {
+                    let mut s = String::new();
+                    if let Err(e) = std::io::stdin().read_to_string(&mut s) {
+                        println!("Failed to read stdin: {e}");
+                        std::process::exit(1);
+                    }
+                    s.trim_end().to_string()
+                }
+            };
+
+            let (config, _) = load_single_account(&cli.config, &cli.options);
 
-        tokio::time::sleep(Duration::from_secs(config.polling_interval)).await;
+            if cli.options.dry_run {
+                println!("Would post: {text}");
+                return;
+            }
+
+            let connection = match wait_until_ready(&config, Duration::ZERO).await {
+                Ok(c) => c,
+                Err(code) => std::process::exit(code),
+            };
+
+            if let Err(e) = connection.client.post(text).await {
+                println!("Failed to post: {e}");
+                std::process::exit(1);
+            }
+        }
+        Command::Help => println!("{}", cli::USAGE),
     }
 }
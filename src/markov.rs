@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+///Marks the start and the end of a text in the chain
+const BOUNDARY: &str = "";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MarkovOptions {
+    ///Text file the chain is built from, every line is a separate text, for example one post per
+    ///line
+    pub corpus: String,
+    ///How many previous words are used to pick the next one, higher values stick closer to the
+    ///corpus
+    #[serde(default = "default_order")]
+    pub order: usize,
+    ///Seed for the random generator, makes the generated texts of this response reproducible.
+    ///Can't be combined with the `seed` of the account, which does that for every reply
+    pub seed: Option<u64>,
+}
+
+fn default_order() -> usize {
+    2
+}
+
+///Order-N markov chain over words
+#[derive(Debug)]
+pub struct Markov {
+    order: usize,
+    ///Previous `order` words to every word that followed them in the corpus
+    chain: HashMap<Vec<String>, Vec<String>>,
+    ///Only set if the options have a seed
+    rng: Option<Mutex<StdRng>>,
+}
+
+impl Markov {
+    ///Reads the corpus and builds the chain
+    pub fn load(options: &MarkovOptions) -> Result<Self, String> {
+        let corpus = std::fs::read_to_string(&options.corpus)
+            .map_err(|e| format!("failed to read {}: {e}", options.corpus))?;
+
+        Self::build(&corpus, options)
+    }
+
+    pub fn build(corpus: &str, options: &MarkovOptions) -> Result<Self, String> {
+        if options.order == 0 {
+            return Err("order must be at least 1".into());
+        }
+
+        let mut chain = HashMap::<Vec<String>, Vec<String>>::new();
+
+        for line in corpus.lines() {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+
+            if words.is_empty() {
+                continue;
+            }
+
+            let mut state = vec![BOUNDARY.to_string(); options.order];
+
+            for word in words.into_iter().chain([BOUNDARY]) {
+                chain.entry(state.clone()).or_default().push(word.into());
+
+                state.remove(0);
+                state.push(word.into());
+            }
+        }
+
+        if chain.is_empty() {
+            return Err(format!("{} has no words", options.corpus));
+        }
+
+        Ok(Self {
+            order: options.order,
+            chain,
+            rng: options.seed.map(|i| Mutex::new(StdRng::seed_from_u64(i))),
+        })
+    }
+
+    ///Generates between `min_words` and `max_words` words, starting a new text from the corpus if
+    ///one ends too early
+    pub fn generate(&self, min_words: u32, max_words: u32, rng: &mut impl Rng) -> (String, u32) {
+        match &self.rng {
+            Some(r) => self.walk(min_words, max_words, &mut *r.lock().unwrap()),
+            None => self.walk(min_words, max_words, rng),
+        }
+    }
+
+    fn walk(&self, min_words: u32, max_words: u32, rng: &mut impl Rng) -> (String, u32) {
+        let start = vec![BOUNDARY.to_string(); self.order];
+
+        let mut state = start.clone();
+        let mut words = Vec::new();
+
+        while words.len() < max_words as usize {
+            //Every state that was reached has at least one word after it
+            let next = &self.chain[&state];
+            let next = &next[rng.random_range(0..next.len())];
+
+            if next == BOUNDARY {
+                if words.len() >= min_words as usize {
+                    break;
+                }
+
+                state = start.clone();
+                continue;
+            }
+
+            words.push(next.as_str());
+
+            state.remove(0);
+            state.push(next.clone());
+        }
+
+        (words.join(" "), words.len() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(order: usize, seed: Option<u64>) -> MarkovOptions {
+        MarkovOptions {
+            corpus: "corpus.txt".into(),
+            order,
+            seed,
+        }
+    }
+
+    const CORPUS: &str = "the cat sat on the mat
+the dog sat on the log
+
+the cat ate the fish
+a dog ate a bone";
+
+    #[test]
+    fn follows_the_corpus() {
+        let markov = Markov::build("a b c", &options(1, None)).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        assert_eq!(markov.generate(1, 10, &mut rng), ("a b c".into(), 3));
+        assert_eq!(markov.generate(1, 2, &mut rng), ("a b".into(), 2));
+        //Texts that end too early are followed by another one
+        assert_eq!(markov.generate(5, 10, &mut rng), ("a b c a b c".into(), 6));
+    }
+
+    #[test]
+    fn only_joins_words_that_follow_each_other() {
+        let markov = Markov::build(CORPUS, &options(1, None)).unwrap();
+        let pairs = CORPUS
+            .lines()
+            .flat_map(|i| {
+                let words = i.split_whitespace().collect::<Vec<_>>();
+                words
+                    .windows(2)
+                    .map(|i| (i[0].to_string(), i[1].to_string()))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for _ in 0..100 {
+            let (text, count) = markov.generate(1, 20, &mut rng);
+            let words = text.split_whitespace().collect::<Vec<_>>();
+
+            assert_eq!(words.len() as u32, count);
+            assert!((1..=20).contains(&count));
+            assert!(["the", "a"].contains(&words[0]), "{text}");
+
+            //Pairs across two texts aren't in the corpus
+            for pair in words.windows(2) {
+                if !["the", "a"].contains(&pair[1]) {
+                    assert!(pairs.contains(&(pair[0].into(), pair[1].into())), "{text}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn higher_orders_stick_to_the_corpus() {
+        let markov = Markov::build(CORPUS, &options(4, None)).unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+
+        //Four words of context tell every line of the corpus apart, so they can only be repeated
+        for _ in 0..50 {
+            let (text, _) = markov.generate(1, 20, &mut rng);
+            assert!(CORPUS.lines().any(|i| i == text), "{text}");
+        }
+    }
+
+    #[test]
+    fn seed_makes_texts_reproducible() {
+        let generate = || {
+            let markov = Markov::build(CORPUS, &options(1, Some(7))).unwrap();
+            let mut rng = rand::rng();
+
+            (0..10)
+                .map(|_| markov.generate(1, 20, &mut rng).0)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(generate(), generate());
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        assert!(Markov::build(CORPUS, &options(0, None)).is_err());
+        assert!(Markov::build("\n  \n", &options(2, None)).is_err());
+    }
+}
@@ -1,13 +1,36 @@
-use futures_util::TryStreamExt;
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use futures_util::{Stream, StreamExt, TryStreamExt};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::from_str;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
 use tokio::io::AsyncBufReadExt;
+use tokio::sync::mpsc;
 use tokio_util::io::StreamReader;
 
+use crate::error::{ClientError, check_response, parse_json, parse_response};
+use crate::fediverse::{self, FediverseClient, Payload};
+use crate::request::{RateLimit, RequestLayer};
+use crate::streaming::{self, StreamError, StreamItem};
+
+///Mastodon sends a heartbeat every 15 seconds, so this long without anything means the
+///connection is dead
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
 pub struct Client {
     token: String,
     url: String,
-    client: reqwest::Client,
+    client: RequestLayer,
+}
+
+///Posts above and below a status in its thread
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct Context {
+    pub ancestors: Vec<Status>,
+    pub descendants: Vec<Status>,
 }
 
 #[derive(Default, Serialize, Deserialize, Debug)]
@@ -19,6 +42,9 @@ pub enum Visibility {
     unlisted,
     private,
     direct,
+    //Pleroma adds local and list, don't fail on them
+    #[serde(other)]
+    unknown,
 }
 
 #[derive(Default, Serialize, Deserialize, Debug)]
@@ -63,7 +89,8 @@ pub struct Account {
     pub username: String,
     pub acct: String,
     pub url: String,
-    pub uri: String,
+    ///Not sent by pleroma, see `pleroma.ap_id`
+    pub uri: Option<String>,
     pub display_name: String,
     pub note: String,
     pub avatar: String,
@@ -85,6 +112,22 @@ pub struct Account {
     pub followers_count: i32,
     pub following_count: i32,
     pub hide_collections: Option<bool>,
+    ///Only sent by pleroma and akkoma
+    pub pleroma: Option<AccountPleroma>,
+}
+
+///Fields pleroma adds to accounts
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct AccountPleroma {
+    ///Activitypub id of the account
+    pub ap_id: Option<String>,
+    pub is_admin: bool,
+    pub is_moderator: bool,
+    pub tags: Vec<String>,
+    ///Whether the account can be sent chat messages
+    pub accepts_chat_messages: Option<bool>,
+    pub favicon: Option<String>,
 }
 
 #[derive(Default, Serialize, Deserialize, Debug)]
@@ -182,7 +225,7 @@ pub struct PreviewCard {
     pub blurhash: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 #[allow(non_camel_case_types)]
 pub enum QuoteState {
     pending,
@@ -191,21 +234,35 @@ pub enum QuoteState {
     revoked,
     deleted,
     unauthorized,
+    blocked_account,
+    blocked_domain,
+    muted_account,
+    //Newer versions add their own states, don't fail the whole status on them
+    #[serde(other)]
+    unknown,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-#[allow(non_camel_case_types)]
+#[serde(untagged)]
 pub enum Quote {
-    Quote {
+    ShallowQuote {
         state: QuoteState,
-        status: Option<Box<Status>>,
+        quoted_status_id: String,
     },
-    ShallowQuote {
+    Quote {
         state: QuoteState,
-        status_id: Option<String>,
+        quoted_status: Option<Box<Status>>,
     },
 }
 
+///Sharkey puts the quoted status itself in `quote`, mastodon wraps it with the state of the quote
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+pub enum StatusQuote {
+    Sharkey(Box<Status>),
+    Mastodon(Quote),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[allow(non_camel_case_types)]
 pub enum FilterContext {
@@ -303,16 +360,46 @@ pub struct Status {
     pub language: Option<String>,
     pub text: Option<String>,
     pub edited_at: Option<String>,
-    // Had to change the quotes to be this bc sharkey is broken?
-    // pub quote: Option<Quote>,
+    ///Only sent by sharkey
     pub quote_id: Option<String>,
-    pub quote: Option<Box<Status>>,
+    pub quote: Option<StatusQuote>,
     pub favourited: Option<bool>,
     pub reblogged: Option<bool>,
     pub muted: Option<bool>,
     pub bookmarked: Option<bool>,
     pub pinned: Option<bool>,
     pub filtered: Option<Vec<FilterResult>>,
+    ///Only sent by pleroma and akkoma
+    pub pleroma: Option<StatusPleroma>,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct EmojiReaction {
+    ///The emoji itself, or the shortcode of a custom one
+    pub name: String,
+    pub count: i32,
+    ///Whether the user reacted with it
+    pub me: bool,
+    ///Image of a custom emoji
+    pub url: Option<String>,
+}
+
+///Fields pleroma adds to statuses
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(default)]
+pub struct StatusPleroma {
+    pub local: bool,
+    pub in_reply_to_account_acct: Option<String>,
+    ///Content type to the content in that type, like `text/plain`
+    pub content: HashMap<String, String>,
+    ///Content type to the spoiler text in that type
+    pub spoiler_text: HashMap<String, String>,
+    pub expires_at: Option<String>,
+    pub thread_muted: Option<bool>,
+    pub emoji_reactions: Vec<EmojiReaction>,
+    ///Whether the status it replied to is visible to the user
+    pub parent_visible: Option<bool>,
+    pub pinned_at: Option<String>,
 }
 
 #[derive(Default, Serialize, Deserialize, Debug)]
@@ -355,6 +442,13 @@ pub enum NotificationType {
     update,
     severed_relationships,
     moderation_warning,
+    #[serde(rename = "pleroma:emoji_reaction")]
+    pleroma_emoji_reaction,
+    #[serde(rename = "pleroma:chat_mention")]
+    pleroma_chat_mention,
+    //Newer versions and forks add their own types, don't fail on them
+    #[serde(other)]
+    unknown,
 }
 #[derive(Default, Serialize, Deserialize, Debug)]
 //For proper serialization
@@ -428,101 +522,1047 @@ pub struct Notification {
     pub report: Option<Report>,
     pub event: Option<RelationshipSeveranceEvent>,
     pub moderation_warning: Option<AccountWarning>,
+    ///Set on `pleroma:chat_mention` notifications
+    pub chat_message: Option<ChatMessage>,
+}
+
+///A pleroma chat message
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct ChatMessage {
+    pub id: String,
+    pub chat_id: String,
+    pub account_id: String,
+    ///Html, `None` if the message is only an attachment
+    pub content: Option<String>,
+    pub created_at: String,
+    pub emojis: Vec<CustomEmoji>,
+    pub unread: Option<bool>,
+}
+
+#[derive(Default, Serialize, Debug)]
+pub struct NotificationsQuery {
+    ///Only return notifications older than this id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_id: Option<String>,
+    ///Only return notifications newer than this id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since_id: Option<String>,
+    ///Only return notifications immediately newer than this id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    ///Only return notifications of these types, all of them if empty. Sent separately, a struct
+    ///can't be serialized into repeated `types[]` parameters
+    #[serde(skip)]
+    pub types: Vec<NotificationType>,
+}
+
+///One page of a list endpoint
+#[derive(Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    ///Url of the page with older items
+    pub next: Option<String>,
+    ///Url of the page with newer items
+    pub prev: Option<String>,
+}
+
+///Which `Link` to follow when paginating
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageDirection {
+    ///Follow `rel="next"`, items come newest first
+    #[default]
+    Older,
+    ///Follow `rel="prev"`, items come oldest first
+    Newer,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StreamTransport {
+    ///`/api/v1/streaming/user/notification`
+    #[default]
+    ServerSentEvents,
+    ///`/api/v1/streaming`
+    WebSocket,
+}
+
+///Event received from the streaming api
+#[derive(Debug)]
+pub enum StreamEvent {
+    Notification(Box<Notification>),
+    ///A new status appeared
+    Update(Box<Status>),
+    ///Id of a deleted status
+    Delete(String),
+    ///A status was edited
+    StatusUpdate(Box<Status>),
+}
+
+#[derive(Deserialize, Debug)]
+struct WebSocketMessage {
+    event: String,
+    ///Json encoded as a string
+    #[serde(default)]
+    payload: String,
 }
 
 impl Client {
     pub fn new(token: String, url: String) -> Self {
-        let client = reqwest::Client::new();
+        Self::with_request_layer(token, url, RequestLayer::new())
+    }
+
+    ///Uses an existing request layer, so the rate limit is shared with another client
+    pub fn with_request_layer(token: String, url: String, client: RequestLayer) -> Self {
         Self { token, url, client }
     }
 
-    pub async fn create_post(&self, post: Post) -> Result<(), String> {
-        let request = self
+    pub async fn create_post(&self, post: Post) -> Result<(), ClientError> {
+        let mut request = self
             .client
             .post(self.url.clone() + "/api/v1/statuses")
             .bearer_auth(self.token.clone())
             .json(&post);
 
-        match request.send().await.unwrap().error_for_status() {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.to_string()),
+        //The bot replies once per post, so a retry after a server error can't double post
+        if let Some(id) = &post.in_reply_to_id {
+            request = request.header("Idempotency-Key", format!("grok-reply-{id}"));
         }
+
+        check_response(self.client.send_once(request).await?).await?;
+        Ok(())
     }
 
-    pub async fn me(&self) -> Result<String, String> {
+    pub async fn me(&self) -> Result<Account, ClientError> {
         let request = self
             .client
             .get(self.url.clone() + "/api/v1/accounts/verify_credentials")
             .bearer_auth(self.token.clone());
 
-        match request.send().await.unwrap().error_for_status() {
-            Ok(r) => {
-                let text = r.text().await.unwrap();
-                Ok(text)
-            }
-            Err(e) => Err(e.to_string()),
-        }
+        parse_response(self.client.send(request).await?).await
     }
 
+    ///Streams the user's notifications, reconnecting with a growing delay whenever the connection
+    ///drops. The stream ends if the server doesn't support streaming, callers should fall back to
+    ///polling then
     //NOT SUPPORTED BY SHARKEY
-    pub async fn stream_notifications(&self) {
+    pub fn stream_notifications(
+        &self,
+        transport: StreamTransport,
+    ) -> impl Stream<Item = StreamItem<StreamEvent>> + Send + 'static {
+        let client = self.clone();
+
+        streaming::reconnecting(move |tx| {
+            let client = client.clone();
+
+            async move {
+                match transport {
+                    StreamTransport::ServerSentEvents => client.read_event_stream(&tx).await,
+                    StreamTransport::WebSocket => client.read_websocket(&tx).await,
+                }
+            }
+        })
+    }
+
+    ///Reads server-sent events from `/api/v1/streaming/user/notification`
+    async fn read_event_stream(
+        &self,
+        tx: &mpsc::Sender<StreamItem<StreamEvent>>,
+    ) -> Result<(), StreamError> {
         let request = self
             .client
             .get(self.url.clone() + "/api/v1/streaming/user/notification")
+            .header("Accept", "text/event-stream")
             .bearer_auth(self.token.clone());
 
-        let response = request.send().await.unwrap();
+        let response = request
+            .send()
+            .await
+            .map_err(|e| StreamError::Failed(e.to_string()))?;
+
+        streaming::check_status(response.status())?;
+
+        let is_event_stream = response
+            .headers()
+            .get("Content-Type")
+            .and_then(|i| i.to_str().ok())
+            .is_some_and(|i| i.starts_with("text/event-stream"));
+
+        if !is_event_stream {
+            return Err(StreamError::Fatal(
+                "the response is not an event stream".into(),
+            ));
+        }
+
+        println!("Connected to the streaming api");
+
+        if tx.send(StreamItem::Connected).await.is_err() {
+            return Ok(());
+        }
+
+        let stream = StreamReader::new(
+            response
+                .bytes_stream()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::BrokenPipe, format!("{e}"))),
+        );
+
+        let mut lines = stream.lines();
 
-        let stream = StreamReader::new(response.bytes_stream().map_err(|e| {
-            println!("{e}");
-            std::io::Error::new(std::io::ErrorKind::BrokenPipe, format!("{e}"))
-        }));
+        let mut event = String::new();
+        let mut data = String::new();
 
-        let mut stream = stream.lines();
+        loop {
+            let line = match tokio::time::timeout(HEARTBEAT_TIMEOUT, lines.next_line()).await {
+                Ok(Ok(Some(l))) => l,
+                Ok(Ok(None)) => return Err(StreamError::Disconnected("connection closed".into())),
+                Ok(Err(e)) => return Err(StreamError::Disconnected(e.to_string())),
+                Err(_) => return Err(StreamError::Disconnected("missed heartbeats".into())),
+            };
 
-        for _ in 0..4 {
-            let l = stream.next_line().await; //.unwrap().unwrap();
-            println!("Got an item");
-            if let Err(e) = l {
-                println!("ERROR {e}");
-            } else {
-                let l = l.unwrap().unwrap();
+            //An empty line ends the event
+            if line.is_empty() {
+                if !event.is_empty()
+                    && let Some(e) = parse_stream_event(&event, &data)
+                    && tx.send(StreamItem::Event(e)).await.is_err()
+                {
+                    return Ok(());
+                }
 
-                println!("Got line {l}");
+                event.clear();
+                data.clear();
+                continue;
+            }
+
+            //Lines starting with a colon are comments, mastodon uses them as heartbeats
+            if line.starts_with(':') {
+                continue;
+            }
+
+            let (field, value) = line.split_once(':').unwrap_or((&line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+
+            match field {
+                "event" => event = value.into(),
+                "data" => {
+                    if !data.is_empty() {
+                        data.push('\n');
+                    }
+                    data += value;
+                }
+                _ => {}
             }
         }
     }
 
-    pub async fn dismiss_all_notification(&self) {
+    ///Reads events from the `user:notification` stream of `/api/v1/streaming`
+    async fn read_websocket(
+        &self,
+        tx: &mpsc::Sender<StreamItem<StreamEvent>>,
+    ) -> Result<(), StreamError> {
+        let url = self
+            .url
+            .replacen("https://", "wss://", 1)
+            .replacen("http://", "ws://", 1)
+            + &format!(
+                "/api/v1/streaming?access_token={}&stream=user:notification",
+                self.token
+            );
+
+        let mut socket = streaming::connect_websocket(&url).await?;
+
+        streaming::read_websocket(&mut socket, tx, parse_websocket_message).await
+    }
+
+    pub async fn dismiss_all_notification(&self) -> Result<(), ClientError> {
         let request = self
             .client
             .post(self.url.clone() + "/api/v1/notifications/clear")
             .bearer_auth(self.token.clone());
 
-        let r = request.send().await.unwrap().text().await.unwrap();
+        check_response(self.client.send(request).await?).await?;
+        Ok(())
+    }
 
-        println!("DISMIS: {r}");
+    ///Moves the read marker of the notifications to `last_read_id`
+    pub async fn set_notifications_marker(&self, last_read_id: String) -> Result<(), ClientError> {
+        let request = self
+            .client
+            .post(self.url.clone() + "/api/v1/markers")
+            .bearer_auth(self.token.clone())
+            .json(&serde_json::json!({ "notifications": { "last_read_id": last_read_id } }));
+
+        check_response(self.client.send(request).await?).await?;
+        Ok(())
     }
 
-    pub async fn dismis_notification(&self, id: String) {
+    pub async fn dismis_notification(&self, id: String) -> Result<(), ClientError> {
         let request = self
             .client
             .post(self.url.clone() + &format!("/api/v1/notifications/dismiss?id={id}"))
             .bearer_auth(self.token.clone());
 
-        let r = request.send().await.unwrap().text().await.unwrap();
-
-        println!("DISMIS: {r}");
+        check_response(self.client.send(request).await?).await?;
+        Ok(())
     }
 
-    pub async fn get_notifications(&self) -> Vec<Notification> {
+    pub async fn get_notifications(
+        &self,
+        query: NotificationsQuery,
+    ) -> Result<Vec<Notification>, ClientError> {
         let request = self
             .client
             .get(self.url.clone() + "/api/v1/notifications")
+            .query(&query)
+            .query(&types_query(&query.types))
             .bearer_auth(self.token.clone());
 
-        let response = request.send().await.unwrap().text().await.unwrap();
+        parse_response(self.client.send(request).await?).await
+    }
+
+    ///Fetches a single page, `url` is either built from a path or taken from a previous page
+    pub async fn get_page<T: DeserializeOwned>(&self, url: &str) -> Result<Page<T>, ClientError> {
+        let request = self.client.get(url).bearer_auth(self.token.clone());
+
+        let response = check_response(self.client.send(request).await?).await?;
+
+        let (next, prev) = response
+            .headers()
+            .get("Link")
+            .and_then(|i| i.to_str().ok())
+            .map(parse_link_header)
+            .unwrap_or_default();
+
+        let text = response.text().await?;
+
+        Ok(Page {
+            items: parse_json(&text)?,
+            next,
+            prev,
+        })
+    }
+
+    ///Streams every item of a list endpoint like `/api/v1/notifications` or
+    ///`/api/v1/accounts/:id/followers`, fetching pages as needed by following the `Link` headers
+    pub fn paginate<T: DeserializeOwned + Send + 'static>(
+        &self,
+        path: &str,
+        query: &impl Serialize,
+        direction: PageDirection,
+    ) -> impl Stream<Item = Result<T, ClientError>> + Send + 'static {
+        let client = self.clone();
+
+        let first = self
+            .client
+            .get(self.url.clone() + path)
+            .query(query)
+            .build()
+            .map(|i| i.url().to_string())
+            .map_err(ClientError::from);
+
+        futures_util::stream::unfold(
+            (Some(first), VecDeque::new()),
+            move |(mut url, mut items)| {
+                let client = client.clone();
+
+                async move {
+                    loop {
+                        if let Some(item) = items.pop_front() {
+                            return Some((Ok(item), (url, items)));
+                        }
+
+                        let page = match url.take()? {
+                            Ok(u) => client.get_page::<T>(&u).await,
+                            Err(e) => Err(e),
+                        };
+
+                        let page = match page {
+                            Ok(p) => p,
+                            //Stop after an error
+                            Err(e) => return Some((Err(e), (None, items))),
+                        };
+
+                        if page.items.is_empty() {
+                            return None;
+                        }
+
+                        //Pages are always newest first
+                        match direction {
+                            PageDirection::Older => {
+                                items = page.items.into();
+                                url = page.next.map(Ok);
+                            }
+                            PageDirection::Newer => {
+                                items = page.items.into_iter().rev().collect();
+                                url = page.prev.map(Ok);
+                            }
+                        }
+                    }
+                }
+            },
+        )
+    }
+
+    ///Fetches every notification of the given types newer than `min_id`, newest first
+    pub async fn get_notifications_since(
+        &self,
+        min_id: String,
+        types: &[NotificationType],
+    ) -> Result<Vec<Notification>, ClientError> {
+        let mut query = types_query(types);
+        query.push(("min_id", min_id));
+
+        let mut notifications = self
+            .paginate("/api/v1/notifications", &query, PageDirection::Newer)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        notifications.reverse();
+
+        Ok(notifications)
+    }
+
+    pub async fn get_status(&self, id: String) -> Result<Status, ClientError> {
+        let request = self
+            .client
+            .get(self.url.clone() + &format!("/api/v1/statuses/{id}"))
+            .bearer_auth(self.token.clone());
+
+        parse_response(self.client.send(request).await?).await
+    }
+
+    pub async fn get_context(&self, id: String) -> Result<Context, ClientError> {
+        let request = self
+            .client
+            .get(self.url.clone() + &format!("/api/v1/statuses/{id}/context"))
+            .bearer_auth(self.token.clone());
+
+        parse_response(self.client.send(request).await?).await
+    }
+
+    pub async fn favourite(&self, id: String) -> Result<(), ClientError> {
+        let request = self
+            .client
+            .post(self.url.clone() + &format!("/api/v1/statuses/{id}/favourite"))
+            .bearer_auth(self.token.clone());
+
+        check_response(self.client.send(request).await?).await?;
+        Ok(())
+    }
+}
+
+///Builds the `types[]` parameters that filter notifications
+fn types_query(types: &[NotificationType]) -> Vec<(&'static str, String)> {
+    types
+        .iter()
+        .filter_map(|i| serde_json::to_value(i).ok()?.as_str().map(String::from))
+        .map(|i| ("types[]", i))
+        .collect()
+}
+
+///Converts a mention notification into a platform independent mention
+pub fn to_mention(notification: Notification) -> Option<fediverse::Mention> {
+    if notification.r#type != NotificationType::mention {
+        return None;
+    }
+
+    let status = notification.status?;
+
+    Some(fediverse::Mention {
+        id: notification.id,
+        author: notification.account.into(),
+        //Vanilla mastodon only has the source text right after posting or deleting
+        text: Some(
+            plain_text(status.text, status.pleroma).unwrap_or_else(|| strip_html(&status.content)),
+        ),
+        status_id: status.id,
+        visibility: status.visibility.into(),
+        mentions: status.mentions.into_iter().map(Into::into).collect(),
+        in_reply_to_id: status.in_reply_to_id,
+        chat_id: None,
+    })
+}
+
+///Returns the source of the status, or the plain text version pleroma sends instead
+fn plain_text(text: Option<String>, pleroma: Option<StatusPleroma>) -> Option<String> {
+    text.or_else(|| pleroma?.content.remove("text/plain"))
+}
+
+///Turns the html content of a status into plain text, dropping the tags
+pub fn strip_html(content: &str) -> String {
+    let content = content
+        .replace("<br>", "\n")
+        .replace("<br/>", "\n")
+        .replace("<br />", "\n")
+        .replace("</p><p>", "\n\n");
+
+    let mut text = String::new();
+    let mut in_tag = false;
+
+    for c in content.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+impl From<Status> for fediverse::Post {
+    fn from(value: Status) -> Self {
+        Self {
+            id: value.id,
+            author: value.account.into(),
+            //Only statuses that were just posted or deleted have the source text
+            text: Some(
+                plain_text(value.text, value.pleroma).unwrap_or_else(|| strip_html(&value.content)),
+            ),
+            in_reply_to_id: value.in_reply_to_id,
+            media: value
+                .media_attachments
+                .iter()
+                .map(|i| match i.r#type {
+                    MediaType::image => fediverse::MediaKind::image,
+                    MediaType::gifv | MediaType::video => fediverse::MediaKind::video,
+                    MediaType::audio => fediverse::MediaKind::audio,
+                    MediaType::unknown => fediverse::MediaKind::other,
+                })
+                .collect(),
+            poll: value
+                .poll
+                .map(|i| i.options.into_iter().map(|i| i.title).collect()),
+        }
+    }
+}
+
+///Builds the post replying to the mention, with the same visibility
+fn reply_post(mention: &fediverse::Mention, text: String) -> Post {
+    Post {
+        status: text,
+        in_reply_to_id: Some(mention.status_id.clone()),
+        visibility: Some(mention.visibility.into()),
+        ..Default::default()
+    }
+}
+
+///Parses a `Link` header into the urls of the next and previous pages
+fn parse_link_header(value: &str) -> (Option<String>, Option<String>) {
+    let mut next = None;
+    let mut prev = None;
+
+    //<https://example.com/api/v1/notifications?max_id=1>; rel="next", <...>; rel="prev"
+    for link in value.split(',') {
+        let mut parts = link.split(';');
+
+        let Some(url) = parts.next() else {
+            continue;
+        };
+
+        let url = url.trim().trim_start_matches('<').trim_end_matches('>');
+
+        for param in parts {
+            match param.trim() {
+                "rel=\"next\"" | "rel=next" => next = Some(url.to_string()),
+                "rel=\"prev\"" | "rel=prev" => prev = Some(url.to_string()),
+                _ => {}
+            }
+        }
+    }
+
+    (next, prev)
+}
+
+///Parses a message of the websocket, which wraps the same events as the server-sent ones
+fn parse_websocket_message(text: &str) -> Option<StreamEvent> {
+    let message = from_str::<WebSocketMessage>(text).ok()?;
+    parse_stream_event(&message.event, &message.payload)
+}
+
+fn parse_stream_event(event: &str, payload: &str) -> Option<StreamEvent> {
+    let parsed = match event {
+        "notification" => parse_json(payload).map(StreamEvent::Notification),
+        "update" => parse_json(payload).map(StreamEvent::Update),
+        "status.update" => parse_json(payload).map(StreamEvent::StatusUpdate),
+        //The payload of a delete is just the id
+        "delete" => Ok(StreamEvent::Delete(payload.into())),
+        _ => return None,
+    };
+
+    match parsed {
+        Ok(e) => Some(e),
+        Err(e) => {
+            println!("Failed to parse a {event} event: {e}");
+            None
+        }
+    }
+}
+
+impl From<Visibility> for fediverse::Visibility {
+    fn from(value: Visibility) -> Self {
+        match value {
+            Visibility::public => Self::Public,
+            Visibility::unlisted => Self::Unlisted,
+            Visibility::private => Self::Followers,
+            Visibility::direct => Self::Direct,
+            //Local only and list posts, keep the reply to fewer people rather than more
+            Visibility::unknown => Self::Followers,
+        }
+    }
+}
+
+impl From<fediverse::Visibility> for Visibility {
+    fn from(value: fediverse::Visibility) -> Self {
+        match value {
+            fediverse::Visibility::Public => Self::public,
+            fediverse::Visibility::Unlisted => Self::unlisted,
+            fediverse::Visibility::Followers => Self::private,
+            fediverse::Visibility::Direct => Self::direct,
+        }
+    }
+}
+
+impl From<Account> for fediverse::Account {
+    fn from(value: Account) -> Self {
+        //acct is `user` for local accounts and `user@instance` for remote ones
+        let host = value.acct.split_once('@').map(|(_, host)| host.to_string());
+
+        Self {
+            id: value.id,
+            username: value.username,
+            host,
+            bot: value.bot,
+        }
+    }
+}
+
+impl From<Mention> for fediverse::Account {
+    fn from(value: Mention) -> Self {
+        // https://lunar.place/@luna
+        let binding = value.url.split("https://").collect::<Vec<_>>();
+
+        //lunar.place/@luna
+        let instance = binding.last().unwrap().split("/").next().unwrap();
+
+        Self {
+            host: Some(instance.into()),
+            id: value.id,
+            username: value.username,
+            bot: false,
+        }
+    }
+}
+
+#[async_trait]
+impl FediverseClient for Client {
+    async fn whoami(&self) -> Result<fediverse::Account, ClientError> {
+        Ok(self.me().await?.into())
+    }
+
+    async fn fetch_mentions(
+        &self,
+        since_id: Option<String>,
+    ) -> Result<Vec<fediverse::Mention>, ClientError> {
+        //Everything else would be paged through again on every check, as the cursor only moves
+        //with mentions
+        let types = vec![NotificationType::mention];
+
+        let notifications = match since_id {
+            Some(id) => self.get_notifications_since(id, &types).await?,
+            //Without a cursor only the newest page is relevant, not the whole history
+            None => {
+                self.get_notifications(NotificationsQuery {
+                    types,
+                    ..Default::default()
+                })
+                .await?
+            }
+        };
+
+        Ok(notifications.into_iter().filter_map(to_mention).collect())
+    }
+
+    async fn fetch_post(&self, id: String) -> Result<fediverse::Post, ClientError> {
+        Ok(self.get_status(id).await?.into())
+    }
+
+    async fn fetch_thread(
+        &self,
+        mention: &fediverse::Mention,
+    ) -> Result<Vec<fediverse::Post>, ClientError> {
+        let context = self.get_context(mention.status_id.clone()).await?;
+
+        Ok(context.ancestors.into_iter().map(Into::into).collect())
+    }
+
+    fn stream_mentions(&self) -> Option<BoxStream<'static, fediverse::MentionEvent>> {
+        let mentions = self
+            .stream_notifications(StreamTransport::ServerSentEvents)
+            .filter_map(|item| async move {
+                match item {
+                    StreamItem::Connected => Some(fediverse::MentionEvent::Connected),
+                    StreamItem::Event(StreamEvent::Notification(n)) => {
+                        to_mention(*n).map(|m| fediverse::MentionEvent::Mention(Box::new(m)))
+                    }
+                    StreamItem::Event(_) => None,
+                }
+            });
+
+        Some(mentions.boxed())
+    }
+
+    async fn post(&self, text: String) -> Result<(), ClientError> {
+        let post = Post {
+            status: text,
+            ..Default::default()
+        };
+
+        self.create_post(post).await
+    }
+
+    async fn reply(&self, mention: &fediverse::Mention, text: String) -> Result<(), ClientError> {
+        self.create_post(reply_post(mention, text)).await
+    }
+
+    fn reply_payload(&self, mention: &fediverse::Mention, text: String) -> Payload {
+        Payload {
+            endpoint: "/api/v1/statuses".into(),
+            body: serde_json::to_value(reply_post(mention, text)).unwrap(),
+        }
+    }
+
+    fn rate_limit(&self) -> RateLimit {
+        self.client.rate_limit()
+    }
+
+    async fn mark_read(&self, last_id: String) -> Result<(), ClientError> {
+        self.set_notifications_marker(last_id).await
+    }
+
+    //Vanilla mastodon has no emoji reactions, favouriting is the closest thing
+    async fn react(
+        &self,
+        mention: &fediverse::Mention,
+        _reaction: String,
+    ) -> Result<(), ClientError> {
+        self.favourite(mention.status_id.clone()).await
+    }
+
+    fn react_payload(&self, mention: &fediverse::Mention, _reaction: String) -> Payload {
+        Payload {
+            endpoint: format!("/api/v1/statuses/{}/favourite", mention.status_id),
+            body: serde_json::Value::Null,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Trimmed from what akkoma 3.13 sends, with fields the bot doesn't read left in
+    const PLEROMA_NOTIFICATIONS: &str = r#"[
+        {
+            "id": "3",
+            "type": "move",
+            "created_at": "2025-01-05T10:00:00.000Z",
+            "account": {
+                "id": "AbC", "username": "bob", "acct": "bob@example.social",
+                "display_name": "bob", "locked": false, "bot": false,
+                "created_at": "2024-01-01T00:00:00.000Z", "note": "",
+                "url": "https://example.social/users/bob",
+                "avatar": "", "avatar_static": "", "header": "", "header_static": "",
+                "emojis": [], "fields": [], "followers_count": 1, "following_count": 2,
+                "statuses_count": 3, "fqn": "bob@example.social",
+                "pleroma": { "ap_id": "https://example.social/users/bob", "is_admin": false }
+            },
+            "target": null,
+            "pleroma": { "is_seen": false, "is_muted": false }
+        },
+        {
+            "id": "2",
+            "type": "mention",
+            "created_at": "2025-01-05T09:00:00.000Z",
+            "account": {
+                "id": "AbD", "username": "alice", "acct": "alice",
+                "display_name": "alice", "locked": false, "bot": false,
+                "created_at": "2024-01-01T00:00:00.000Z", "note": "",
+                "url": "https://pleroma.test/users/alice",
+                "avatar": "", "avatar_static": "", "header": "", "header_static": "",
+                "emojis": [], "fields": [], "followers_count": 0, "following_count": 0,
+                "statuses_count": 10, "fqn": "alice@pleroma.test",
+                "pleroma": {
+                    "ap_id": "https://pleroma.test/users/alice",
+                    "is_admin": true,
+                    "is_moderator": false,
+                    "tags": [],
+                    "accepts_chat_messages": true,
+                    "favicon": null
+                }
+            },
+            "status": {
+                "id": "AqLx", "uri": "https://pleroma.test/objects/1",
+                "url": "https://pleroma.test/notice/AqLx",
+                "created_at": "2025-01-05T09:00:00.000Z",
+                "account": {
+                    "id": "AbD", "username": "alice", "acct": "alice",
+                    "display_name": "alice", "locked": false, "bot": false,
+                    "created_at": "2024-01-01T00:00:00.000Z", "note": "",
+                    "url": "https://pleroma.test/users/alice",
+                    "avatar": "", "avatar_static": "", "header": "", "header_static": "",
+                    "emojis": [], "fields": [], "followers_count": 0, "following_count": 0,
+                    "statuses_count": 10
+                },
+                "content": "<span class=\"h-card\">@grok</span> is this true?",
+                "text": null,
+                "visibility": "local",
+                "sensitive": false, "spoiler_text": "",
+                "media_attachments": [], "mentions": [
+                    { "id": "AbE", "username": "grok", "acct": "grok",
+                      "url": "https://pleroma.test/users/grok" }
+                ],
+                "tags": [], "emojis": [], "application": null,
+                "reblogs_count": 0, "replies_count": 0, "favourites_count": 0,
+                "in_reply_to_id": null, "in_reply_to_account_id": null,
+                "reblog": null, "poll": null, "card": null, "language": null,
+                "pleroma": {
+                    "local": true,
+                    "conversation_id": 12,
+                    "content": { "text/plain": "@grok is this true?" },
+                    "spoiler_text": { "text/plain": "" },
+                    "emoji_reactions": [{ "name": "👍", "count": 1, "me": false }],
+                    "parent_visible": false
+                }
+            },
+            "pleroma": { "is_seen": false, "is_muted": false }
+        },
+        {
+            "id": "1",
+            "type": "pleroma:report",
+            "created_at": "2025-01-05T08:00:00.000Z",
+            "account": {
+                "id": "AbD", "username": "alice", "acct": "alice",
+                "display_name": "alice", "locked": false, "bot": false,
+                "created_at": "2024-01-01T00:00:00.000Z", "note": "",
+                "url": "https://pleroma.test/users/alice",
+                "avatar": "", "avatar_static": "", "header": "", "header_static": "",
+                "emojis": [], "fields": [], "followers_count": 0, "following_count": 0,
+                "statuses_count": 10
+            }
+        }
+    ]"#;
+
+    #[test]
+    fn parses_pleroma_notifications() {
+        let notifications: Vec<Notification> = serde_json::from_str(PLEROMA_NOTIFICATIONS).unwrap();
+
+        assert_eq!(notifications.len(), 3);
+        assert_eq!(notifications[0].r#type, NotificationType::unknown);
+        assert_eq!(notifications[2].r#type, NotificationType::unknown);
+
+        let mentions = notifications
+            .into_iter()
+            .filter_map(to_mention)
+            .collect::<Vec<_>>();
+
+        assert_eq!(mentions.len(), 1);
+        assert_eq!(mentions[0].status_id, "AqLx");
+        assert_eq!(mentions[0].text.as_deref(), Some("@grok is this true?"));
+        assert_eq!(mentions[0].visibility, fediverse::Visibility::Followers);
+        assert_eq!(mentions[0].author.handle(), "alice");
+    }
+
+    #[test]
+    fn parses_unknown_quote_states() {
+        let mut notifications: serde_json::Value =
+            serde_json::from_str(PLEROMA_NOTIFICATIONS).unwrap();
+
+        for (state, quote) in [
+            (
+                QuoteState::muted_account,
+                r#"{"state": "muted_account", "quoted_status_id": "1"}"#,
+            ),
+            (
+                QuoteState::unknown,
+                r#"{"state": "some_future_state", "quoted_status": null}"#,
+            ),
+        ] {
+            notifications[1]["status"]["quote"] = serde_json::from_str(quote).unwrap();
+
+            let notifications: Vec<Notification> =
+                serde_json::from_value(notifications.clone()).unwrap();
+
+            let quote = notifications[1].status.as_ref().unwrap().quote.as_ref();
+            let Some(StatusQuote::Mastodon(
+                Quote::ShallowQuote { state: parsed, .. } | Quote::Quote { state: parsed, .. },
+            )) = quote
+            else {
+                panic!("expected a mastodon quote, got {quote:?}");
+            };
+
+            assert_eq!(parsed, &state);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_the_html_content() {
+        let mut notifications: Vec<Notification> =
+            serde_json::from_str(PLEROMA_NOTIFICATIONS).unwrap();
+        let mut notification = notifications.remove(1);
+
+        //What vanilla mastodon sends
+        let status = notification.status.as_mut().unwrap();
+        status.text = None;
+        status.pleroma = None;
+        status.content = "<p><span class=\"h-card\"><a href=\"https://pleroma.test/users/grok\">@<span>grok</span></a></span> is this true?</p>".into();
+
+        let mention = to_mention(notification).unwrap();
+        assert_eq!(mention.text.as_deref(), Some("@grok is this true?"));
+    }
+
+    #[test]
+    fn builds_the_types_query() {
+        assert_eq!(
+            types_query(&[
+                NotificationType::mention,
+                NotificationType::pleroma_chat_mention
+            ]),
+            [
+                ("types[]", "mention".to_string()),
+                ("types[]", "pleroma:chat_mention".to_string())
+            ]
+        );
+        assert!(types_query(&[]).is_empty());
+    }
+
+    #[test]
+    fn parses_link_headers() {
+        let (next, prev) = parse_link_header(
+            "<https://example.com/api/v1/notifications?max_id=1>; rel=\"next\", \
+             <https://example.com/api/v1/notifications?min_id=9>; rel=\"prev\"",
+        );
+
+        assert_eq!(
+            next.as_deref(),
+            Some("https://example.com/api/v1/notifications?max_id=1")
+        );
+        assert_eq!(
+            prev.as_deref(),
+            Some("https://example.com/api/v1/notifications?min_id=9")
+        );
+
+        let (next, prev) = parse_link_header("<https://example.com/?min_id=9>;rel=prev");
+        assert_eq!(next, None);
+        assert_eq!(prev.as_deref(), Some("https://example.com/?min_id=9"));
+
+        assert_eq!(parse_link_header(""), (None, None));
+        assert_eq!(
+            parse_link_header("<https://example.com/>; rel=\"self\""),
+            (None, None)
+        );
+    }
+
+    ///The notification of the pleroma payload that's a mention, and its status
+    fn mention_notification() -> (serde_json::Value, serde_json::Value) {
+        let notifications: serde_json::Value = serde_json::from_str(PLEROMA_NOTIFICATIONS).unwrap();
+        let notification = notifications[1].clone();
+
+        (notification.clone(), notification["status"].clone())
+    }
+
+    #[tokio::test]
+    async fn reads_server_sent_events() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = Client::new(
+            "token".into(),
+            format!("http://{}", listener.local_addr().unwrap()),
+        );
+        let (notification, status) = mention_notification();
+
+        //Pretty printed, so the notification is split over several data lines
+        let data = serde_json::to_string_pretty(&notification)
+            .unwrap()
+            .lines()
+            .map(|i| format!("data: {i}\n"))
+            .collect::<String>();
+
+        let body = format!(
+            ":thump\n\nevent: notification\n{data}\n:thump\n\nevent: update\ndata:{status}\n\n\
+             event: filters_changed\n\nevent: delete\ndata: 1234\n\n"
+        );
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request).await.unwrap();
+
+            let response = format!(
+                "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n{body}"
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        let items = client
+            .stream_notifications(StreamTransport::ServerSentEvents)
+            .take(4)
+            .collect::<Vec<_>>()
+            .await;
+
+        assert!(matches!(items[0], StreamItem::Connected));
+        assert!(
+            matches!(&items[1], StreamItem::Event(StreamEvent::Notification(n)) if n.id == "2"),
+            "{items:?}"
+        );
+        assert!(
+            matches!(&items[2], StreamItem::Event(StreamEvent::Update(s)) if s.id == "AqLx"),
+            "{items:?}"
+        );
+        assert!(
+            matches!(&items[3], StreamItem::Event(StreamEvent::Delete(id)) if id == "1234"),
+            "{items:?}"
+        );
+    }
+
+    #[test]
+    fn parses_websocket_messages() {
+        let (notification, status) = mention_notification();
+
+        let message = |event: &str, payload: &serde_json::Value| {
+            serde_json::json!({
+                "stream": ["user:notification"],
+                "event": event,
+                //The payload is json in a string
+                "payload": match payload {
+                    serde_json::Value::String(s) => s.clone(),
+                    p => p.to_string(),
+                },
+            })
+            .to_string()
+        };
+
+        assert!(matches!(
+            parse_websocket_message(&message("notification", &notification)),
+            Some(StreamEvent::Notification(n)) if n.id == "2"
+        ));
+        assert!(matches!(
+            parse_websocket_message(&message("status.update", &status)),
+            Some(StreamEvent::StatusUpdate(s)) if s.id == "AqLx"
+        ));
+        assert!(matches!(
+            parse_websocket_message(&message("delete", &"1234".into())),
+            Some(StreamEvent::Delete(id)) if id == "1234"
+        ));
 
-        from_str(&response).unwrap()
+        //Events the bot doesn't use, broken payloads and other messages are skipped
+        assert!(parse_websocket_message(&message("announcement", &notification)).is_none());
+        assert!(parse_websocket_message(&message("notification", &"{".into())).is_none());
+        assert!(parse_websocket_message("not json").is_none());
     }
 }
@@ -1,13 +1,30 @@
-use serde::Serialize;
+use async_trait::async_trait;
+use futures_util::stream::BoxStream;
+use futures_util::{SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::Message;
 
+use crate::error::{ClientError, check_response, parse_response};
+use crate::fediverse::{self, FediverseClient, Payload};
+use crate::request::{RateLimit, RequestLayer};
+use crate::streaming::{self, Socket, StreamError, StreamItem};
+
+///Id used when subscribing to the main channel
+const MAIN_CHANNEL_ID: &str = "main";
+///Most notifications misskey returns at once
+const NOTIFICATIONS_PAGE_SIZE: u32 = 100;
+
+#[derive(Clone)]
 pub struct Client {
     token: String,
     url: String,
-    client: reqwest::Client,
+    client: RequestLayer,
 }
 
-#[derive(Default, Serialize, Debug)]
+#[derive(Default, Serialize, Deserialize, Debug)]
 //For proper serialization
 #[allow(non_camel_case_types)]
 pub enum Visibility {
@@ -18,18 +35,18 @@ pub enum Visibility {
     specified,
 }
 
-#[derive(Default, Serialize, Debug)]
+#[derive(Default, Serialize, Deserialize, Debug)]
 //For proper serialization
 #[allow(non_camel_case_types)]
 pub enum ReactionAcceptance {
     #[default]
-    null,
     likeOnly,
-    nonSenseitiveOnly,
+    nonSensitiveOnly,
     nonSensitiveOnlyForLocalLikeOnlyForRemote,
 }
 
 #[derive(Default, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
 pub struct Poll {
     pub choices: Vec<String>,
     pub multiple: bool,
@@ -38,12 +55,13 @@ pub struct Poll {
 }
 
 #[derive(Default, Serialize, Debug)]
-pub struct Note {
+#[serde(rename_all = "camelCase")]
+pub struct CreateNote {
     pub visibility: Visibility,
     pub visible_user_ids: Vec<String>,
     pub cw: Option<String>,
     pub local_only: bool,
-    pub reaction_acceptance: ReactionAcceptance,
+    pub reaction_acceptance: Option<ReactionAcceptance>,
     pub no_extract_mentions: bool,
     pub no_extract_hashtags: bool,
     pub no_extract_emojis: bool,
@@ -51,56 +69,843 @@ pub struct Note {
     pub renote_id: Option<String>,
     pub channel_id: Option<String>,
     pub text: Option<String>,
-    pub file_ids: Option<String>,
-    pub media_ids: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_ids: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub media_ids: Option<Vec<String>>,
     pub poll: Option<Poll>,
 }
 
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct UserInstance {
+    pub name: Option<String>,
+    pub software_name: Option<String>,
+    pub software_version: Option<String>,
+    pub icon_url: Option<String>,
+    pub favicon_url: Option<String>,
+    pub theme_color: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    pub id: String,
+    pub name: Option<String>,
+    pub username: String,
+    ///`None` if the user is local
+    pub host: Option<String>,
+    pub avatar_url: Option<String>,
+    pub avatar_blurhash: Option<String>,
+    #[serde(default)]
+    pub is_bot: bool,
+    #[serde(default)]
+    pub is_cat: bool,
+    pub instance: Option<UserInstance>,
+    ///Custom emojis used in the name, shortcode to url
+    #[serde(default)]
+    pub emojis: HashMap<String, String>,
+    pub online_status: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DriveFile {
+    pub id: String,
+    pub created_at: String,
+    pub name: String,
+    pub r#type: String,
+    pub md5: String,
+    pub size: i64,
+    pub is_sensitive: bool,
+    pub blurhash: Option<String>,
+    pub url: String,
+    pub thumbnail_url: Option<String>,
+    pub comment: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NotePollChoice {
+    pub text: String,
+    pub votes: i32,
+    #[serde(default)]
+    pub is_voted: bool,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NotePoll {
+    pub multiple: bool,
+    pub expires_at: Option<String>,
+    pub choices: Vec<NotePollChoice>,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Note {
+    pub id: String,
+    pub created_at: String,
+    pub deleted_at: Option<String>,
+    pub text: Option<String>,
+    pub cw: Option<String>,
+    pub user_id: String,
+    pub user: User,
+    pub reply_id: Option<String>,
+    pub renote_id: Option<String>,
+    pub reply: Option<Box<Note>>,
+    pub renote: Option<Box<Note>>,
+    #[serde(default)]
+    pub is_hidden: bool,
+    pub visibility: Visibility,
+    ///Ids of the mentioned users
+    #[serde(default)]
+    pub mentions: Vec<String>,
+    #[serde(default)]
+    pub visible_user_ids: Vec<String>,
+    #[serde(default)]
+    pub file_ids: Vec<String>,
+    #[serde(default)]
+    pub files: Vec<DriveFile>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub poll: Option<NotePoll>,
+    ///Custom emojis used in the note, shortcode to url
+    #[serde(default)]
+    pub emojis: HashMap<String, String>,
+    pub channel_id: Option<String>,
+    #[serde(default)]
+    pub local_only: bool,
+    pub reaction_acceptance: Option<ReactionAcceptance>,
+    ///Reaction to number of times it was used
+    #[serde(default)]
+    pub reactions: HashMap<String, i32>,
+    #[serde(default)]
+    pub reaction_count: i32,
+    #[serde(default)]
+    pub renote_count: i32,
+    #[serde(default)]
+    pub replies_count: i32,
+    ///ActivityPub id of the note, only set for remote notes
+    pub uri: Option<String>,
+    pub url: Option<String>,
+    pub my_reaction: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+//For proper serialization
+#[allow(non_camel_case_types)]
+pub enum NotificationType {
+    note,
+    follow,
+    #[default]
+    mention,
+    reply,
+    renote,
+    quote,
+    reaction,
+    pollEnded,
+    receiveFollowRequest,
+    followRequestAccepted,
+    roleAssigned,
+    achievementEarned,
+    exportCompleted,
+    login,
+    app,
+    test,
+    //Forks add their own types, don't fail on them
+    #[serde(other)]
+    unknown,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Notification {
+    pub id: String,
+    pub created_at: String,
+    pub r#type: NotificationType,
+    pub user_id: Option<String>,
+    pub user: Option<User>,
+    pub note: Option<Note>,
+    ///Set on reaction notifications
+    pub reaction: Option<String>,
+    ///Set on achievement notifications
+    pub achievement: Option<String>,
+    ///Set on app notifications
+    pub header: Option<String>,
+    pub body: Option<String>,
+    pub icon: Option<String>,
+}
+
+#[derive(Default, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NotificationsQuery {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    ///Only return notifications newer than this id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since_id: Option<String>,
+    ///Only return notifications older than this id
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until_id: Option<String>,
+    ///Misskey marks the returned notifications as read unless this is `false`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mark_as_read: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub include_types: Option<Vec<NotificationType>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exclude_types: Option<Vec<NotificationType>>,
+}
+
+///Event received from the main channel of the streaming api
+#[derive(Debug)]
+pub enum StreamEvent {
+    ///A note mentioned the user
+    Mention(Box<Note>),
+    ///A note replied to one of the user's notes
+    Reply(Box<Note>),
+    Notification(Box<Notification>),
+}
+
+#[derive(Deserialize, Debug)]
+struct StreamMessage {
+    r#type: String,
+    body: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct ChannelMessage {
+    id: String,
+    r#type: String,
+    body: serde_json::Value,
+}
+
 impl Client {
     pub fn new(token: String, url: String) -> Self {
-        let client = reqwest::Client::new();
+        let client = RequestLayer::new();
         Self { token, url, client }
     }
 
-    pub async fn create_note(&self, note: Note) -> Result<(), String> {
+    pub async fn create_note(&self, note: CreateNote) -> Result<(), ClientError> {
         let request = self
             .client
             .post(self.url.clone() + "/api/notes/create")
             .bearer_auth(self.token.clone())
             .json(&note);
 
-        match request.send().await.unwrap().error_for_status() {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.to_string()),
-        }
+        check_response(self.client.send_once(request).await?).await?;
+        Ok(())
     }
 
-    pub async fn me(&self) -> Result<String, String> {
+    pub async fn me(&self) -> Result<User, ClientError> {
         let request = self
             .client
             .post(self.url.clone() + "/api/i")
             .bearer_auth(self.token.clone())
             .json(&HashMap::<i32, i32>::new());
 
-        match request.send().await.unwrap().error_for_status() {
-            Ok(r) => {
-                let text = r.text().await.unwrap();
-                Ok(text)
+        parse_response(self.client.send(request).await?).await
+    }
+
+    pub async fn flush_notifications(&self) -> Result<(), ClientError> {
+        let request = self
+            .client
+            .post(self.url.clone() + "/api/notifications/flush")
+            .bearer_auth(self.token.clone())
+            .json(&HashMap::<i32, i32>::new());
+
+        check_response(self.client.send(request).await?).await?;
+        Ok(())
+    }
+
+    ///Fetches every notification newer than `since_id`, newest first, going back page by page
+    pub async fn get_all_notifications_since(
+        &self,
+        since_id: String,
+        include_types: Option<Vec<NotificationType>>,
+    ) -> Result<Vec<Notification>, ClientError> {
+        let mut notifications = Vec::new();
+        let mut until_id = None;
+
+        loop {
+            let page = self
+                .get_notifications(NotificationsQuery {
+                    limit: Some(NOTIFICATIONS_PAGE_SIZE),
+                    since_id: Some(since_id.clone()),
+                    until_id: until_id.clone(),
+                    include_types: include_types.clone(),
+                    //Only mark_read should change the account, not dry runs
+                    mark_as_read: Some(false),
+                    ..Default::default()
+                })
+                .await?;
+
+            let last_page = page.len() < NOTIFICATIONS_PAGE_SIZE as usize;
+            until_id = page.last().map(|i| i.id.clone());
+            notifications.extend(page);
+
+            if last_page || until_id.is_none() {
+                return Ok(notifications);
             }
-            Err(e) => Err(e.to_string()),
         }
     }
 
-    pub async fn flush_notifications(&self) -> Result<(), String> {
+    pub async fn mark_all_notifications_read(&self) -> Result<(), ClientError> {
         let request = self
             .client
-            .post(self.url.clone() + "/api/notifications/flush")
+            .post(self.url.clone() + "/api/notifications/mark-all-as-read")
             .bearer_auth(self.token.clone())
             .json(&HashMap::<i32, i32>::new());
 
-        match request.send().await.unwrap().error_for_status() {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.to_string()),
+        check_response(self.client.send(request).await?).await?;
+        Ok(())
+    }
+
+    pub async fn get_notifications(
+        &self,
+        query: NotificationsQuery,
+    ) -> Result<Vec<Notification>, ClientError> {
+        let request = self
+            .client
+            .post(self.url.clone() + "/api/i/notifications")
+            .bearer_auth(self.token.clone())
+            .json(&query);
+
+        parse_response(self.client.send(request).await?).await
+    }
+
+    pub async fn show_users(&self, ids: Vec<String>) -> Result<Vec<User>, ClientError> {
+        let request = self
+            .client
+            .post(self.url.clone() + "/api/users/show")
+            .bearer_auth(self.token.clone())
+            .json(&json!({ "userIds": ids }));
+
+        parse_response(self.client.send(request).await?).await
+    }
+
+    ///Connects to the streaming api and yields the events of the main channel, reconnecting and
+    ///resubscribing whenever the connection drops. The connection is closed once the stream is
+    ///dropped
+    pub fn stream(&self) -> impl Stream<Item = StreamItem<StreamEvent>> + Send + 'static {
+        let url = self
+            .url
+            .replacen("https://", "wss://", 1)
+            .replacen("http://", "ws://", 1)
+            + &format!("/streaming?i={}", self.token);
+
+        streaming::reconnecting(move |tx| {
+            let url = url.clone();
+
+            async move {
+                let socket = streaming::connect_websocket(&url).await?;
+                read_main_channel(socket, &tx).await
+            }
+        })
+    }
+
+    ///Converts a mention or reply notification into a platform independent mention
+    async fn to_mention(
+        &self,
+        notification: Notification,
+    ) -> Result<Option<fediverse::Mention>, ClientError> {
+        let Some(note) = notification.note else {
+            return Ok(None);
+        };
+
+        //Notes only contain the ids of the mentioned users
+        let mentioned = if note.mentions.is_empty() {
+            Vec::new()
+        } else {
+            self.show_users(note.mentions).await?
+        };
+
+        Ok(Some(fediverse::Mention {
+            id: notification.id,
+            status_id: note.id,
+            author: note.user.into(),
+            text: note.text,
+            visibility: note.visibility.into(),
+            mentions: mentioned.into_iter().map(Into::into).collect(),
+            in_reply_to_id: note.reply_id,
+            chat_id: None,
+        }))
+    }
+
+    pub async fn get_note(&self, note_id: String) -> Result<Note, ClientError> {
+        let request = self
+            .client
+            .post(self.url.clone() + "/api/notes/show")
+            .bearer_auth(self.token.clone())
+            .json(&json!({ "noteId": note_id }));
+
+        parse_response(self.client.send(request).await?).await
+    }
+
+    ///Returns the notes the note replied to, starting from its parent
+    pub async fn get_conversation(&self, note_id: String) -> Result<Vec<Note>, ClientError> {
+        let request = self
+            .client
+            .post(self.url.clone() + "/api/notes/conversation")
+            .bearer_auth(self.token.clone())
+            .json(&json!({ "noteId": note_id }));
+
+        parse_response(self.client.send(request).await?).await
+    }
+
+    pub async fn create_reaction(
+        &self,
+        note_id: String,
+        reaction: String,
+    ) -> Result<(), ClientError> {
+        let request = self
+            .client
+            .post(self.url.clone() + "/api/notes/reactions/create")
+            .bearer_auth(self.token.clone())
+            .json(&json!({ "noteId": note_id, "reaction": reaction }));
+
+        check_response(self.client.send(request).await?).await?;
+        Ok(())
+    }
+}
+
+impl From<Visibility> for fediverse::Visibility {
+    fn from(value: Visibility) -> Self {
+        match value {
+            Visibility::public => Self::Public,
+            Visibility::home => Self::Unlisted,
+            Visibility::followers => Self::Followers,
+            Visibility::specified => Self::Direct,
         }
     }
 }
+
+impl From<fediverse::Visibility> for Visibility {
+    fn from(value: fediverse::Visibility) -> Self {
+        match value {
+            fediverse::Visibility::Public => Self::public,
+            fediverse::Visibility::Unlisted => Self::home,
+            fediverse::Visibility::Followers => Self::followers,
+            fediverse::Visibility::Direct => Self::specified,
+        }
+    }
+}
+
+impl From<User> for fediverse::Account {
+    fn from(value: User) -> Self {
+        Self {
+            id: value.id,
+            username: value.username,
+            host: value.host,
+            bot: value.is_bot,
+        }
+    }
+}
+
+impl From<Note> for fediverse::Post {
+    fn from(value: Note) -> Self {
+        Self {
+            id: value.id,
+            author: value.user.into(),
+            text: value.text,
+            in_reply_to_id: value.reply_id,
+            //Files have a mime type, like `image/png`
+            media: value
+                .files
+                .iter()
+                .map(|i| match i.r#type.split('/').next() {
+                    Some("image") => fediverse::MediaKind::image,
+                    Some("video") => fediverse::MediaKind::video,
+                    Some("audio") => fediverse::MediaKind::audio,
+                    _ => fediverse::MediaKind::other,
+                })
+                .collect(),
+            poll: value
+                .poll
+                .map(|i| i.choices.into_iter().map(|i| i.text).collect()),
+        }
+    }
+}
+
+#[async_trait]
+impl FediverseClient for Client {
+    async fn whoami(&self) -> Result<fediverse::Account, ClientError> {
+        Ok(self.me().await?.into())
+    }
+
+    async fn fetch_mentions(
+        &self,
+        since_id: Option<String>,
+    ) -> Result<Vec<fediverse::Mention>, ClientError> {
+        let include_types = Some(vec![NotificationType::mention, NotificationType::reply]);
+
+        let notifications = match since_id {
+            Some(id) => self.get_all_notifications_since(id, include_types).await?,
+            //Without a cursor only the newest page is relevant, not the whole history
+            None => {
+                self.get_notifications(NotificationsQuery {
+                    include_types,
+                    mark_as_read: Some(false),
+                    ..Default::default()
+                })
+                .await?
+            }
+        };
+
+        let mut mentions = Vec::new();
+
+        for i in notifications {
+            if let Some(mention) = self.to_mention(i).await? {
+                mentions.push(mention);
+            }
+        }
+
+        Ok(mentions)
+    }
+
+    async fn fetch_post(&self, id: String) -> Result<fediverse::Post, ClientError> {
+        Ok(self.get_note(id).await?.into())
+    }
+
+    async fn fetch_thread(
+        &self,
+        mention: &fediverse::Mention,
+    ) -> Result<Vec<fediverse::Post>, ClientError> {
+        let notes = self.get_conversation(mention.status_id.clone()).await?;
+
+        Ok(notes.into_iter().rev().map(Into::into).collect())
+    }
+
+    fn stream_mentions(&self) -> Option<BoxStream<'static, fediverse::MentionEvent>> {
+        let client = self.clone();
+
+        let mentions = self.stream().filter_map(move |event| {
+            let client = client.clone();
+
+            async move {
+                //Mention and reply events don't carry the notification id
+                let notification = match event {
+                    StreamItem::Connected => return Some(fediverse::MentionEvent::Connected),
+                    StreamItem::Event(StreamEvent::Notification(n)) => n,
+                    StreamItem::Event(_) => return None,
+                };
+
+                if !matches!(
+                    notification.r#type,
+                    NotificationType::mention | NotificationType::reply
+                ) {
+                    return None;
+                }
+
+                //Polling picks it up from the cursor
+                match client.to_mention(*notification).await {
+                    Ok(mention) => mention.map(|m| fediverse::MentionEvent::Mention(Box::new(m))),
+                    Err(e) => Some(fediverse::MentionEvent::Missed(e)),
+                }
+            }
+        });
+
+        Some(mentions.boxed())
+    }
+
+    async fn post(&self, text: String) -> Result<(), ClientError> {
+        let note = CreateNote {
+            text: Some(text),
+            ..Default::default()
+        };
+
+        self.create_note(note).await
+    }
+
+    async fn reply(&self, mention: &fediverse::Mention, text: String) -> Result<(), ClientError> {
+        self.create_note(reply_note(mention, text)).await
+    }
+
+    fn reply_payload(&self, mention: &fediverse::Mention, text: String) -> Payload {
+        Payload {
+            endpoint: "/api/notes/create".into(),
+            body: serde_json::to_value(reply_note(mention, text)).unwrap(),
+        }
+    }
+
+    fn rate_limit(&self) -> RateLimit {
+        self.client.rate_limit()
+    }
+
+    //Misskey has no read marker, but everything up to now was fetched already
+    async fn mark_read(&self, _last_id: String) -> Result<(), ClientError> {
+        self.mark_all_notifications_read().await
+    }
+
+    async fn react(
+        &self,
+        mention: &fediverse::Mention,
+        reaction: String,
+    ) -> Result<(), ClientError> {
+        self.create_reaction(mention.status_id.clone(), reaction)
+            .await
+    }
+
+    fn react_payload(&self, mention: &fediverse::Mention, reaction: String) -> Payload {
+        Payload {
+            endpoint: "/api/notes/reactions/create".into(),
+            body: json!({ "noteId": mention.status_id, "reaction": reaction }),
+        }
+    }
+}
+
+///Builds the note replying to the mention, with the same visibility
+fn reply_note(mention: &fediverse::Mention, text: String) -> CreateNote {
+    //Specified notes are only visible to the listed users, so keep everyone in the thread
+    let visible_user_ids = if mention.visibility == fediverse::Visibility::Direct {
+        std::iter::once(&mention.author)
+            .chain(mention.mentions.iter())
+            .map(|i| i.id.clone())
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    CreateNote {
+        visibility: mention.visibility.into(),
+        visible_user_ids,
+        reply_id: Some(mention.status_id.clone()),
+        text: Some(text),
+        ..Default::default()
+    }
+}
+
+///Subscribes to the main channel and forwards its events until the connection fails or the
+///receiver is dropped
+async fn read_main_channel(
+    mut socket: Socket,
+    tx: &mpsc::Sender<StreamItem<StreamEvent>>,
+) -> Result<(), StreamError> {
+    let connect = json!({
+        "type": "connect",
+        "body": { "channel": "main", "id": MAIN_CHANNEL_ID },
+    });
+
+    socket
+        .send(Message::Text(connect.to_string().into()))
+        .await
+        .map_err(|e| StreamError::Disconnected(e.to_string()))?;
+
+    streaming::read_websocket(&mut socket, tx, parse_stream_event).await
+}
+
+fn parse_stream_event(text: &str) -> Option<StreamEvent> {
+    let message = serde_json::from_str::<StreamMessage>(text).ok()?;
+
+    if message.r#type != "channel" {
+        return None;
+    }
+
+    let message = serde_json::from_value::<ChannelMessage>(message.body).ok()?;
+
+    if message.id != MAIN_CHANNEL_ID {
+        return None;
+    }
+
+    let event = match message.r#type.as_str() {
+        "mention" => serde_json::from_value(message.body).map(StreamEvent::Mention),
+        "reply" => serde_json::from_value(message.body).map(StreamEvent::Reply),
+        "notification" => serde_json::from_value(message.body).map(StreamEvent::Notification),
+        _ => return None,
+    };
+
+    match event {
+        Ok(e) => Some(e),
+        Err(e) => {
+            println!("Failed to parse a {} event: {e}", message.r#type);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Trimmed from what misskey 2025.4 sends, with fields the bot doesn't read left in
+    const NOTIFICATIONS: &str = r#"[
+        {
+            "id": "a5xk3p0000",
+            "createdAt": "2025-05-01T10:00:00.000Z",
+            "type": "reply",
+            "userId": "9zzz000001",
+            "user": {
+                "id": "9zzz000001", "name": "Fox", "username": "fox", "host": "fox.example",
+                "avatarUrl": null, "avatarBlurhash": null, "avatarDecorations": [],
+                "isBot": false, "isCat": false, "emojis": {}, "onlineStatus": "unknown",
+                "badgeRoles": []
+            },
+            "note": {
+                "id": "a5xk2z0000",
+                "createdAt": "2025-05-01T10:00:00.000Z",
+                "userId": "9zzz000001",
+                "user": {
+                    "id": "9zzz000001", "name": "Fox", "username": "fox", "host": "fox.example",
+                    "avatarUrl": null, "avatarBlurhash": null, "isBot": false, "isCat": false,
+                    "emojis": {}, "onlineStatus": "unknown"
+                },
+                "text": "@grok is this true?",
+                "cw": null,
+                "visibility": "specified",
+                "localOnly": false,
+                "reactionAcceptance": null,
+                "renoteCount": 0,
+                "repliesCount": 0,
+                "reactionCount": 0,
+                "reactions": {},
+                "reactionEmojis": {},
+                "fileIds": [],
+                "files": [],
+                "replyId": "a5xk1y0000",
+                "renoteId": null,
+                "visibleUserIds": ["9aaa000001"],
+                "clippedCount": 0
+            }
+        },
+        {
+            "id": "a5xk200000",
+            "createdAt": "2025-05-01T09:00:00.000Z",
+            "type": "follow",
+            "userId": "9zzz000002",
+            "user": {
+                "id": "9zzz000002", "name": null, "username": "cat", "host": null,
+                "avatarUrl": null, "avatarBlurhash": null, "isBot": true, "isCat": true,
+                "emojis": {}, "onlineStatus": "online"
+            }
+        },
+        {
+            "id": "a5xk100000",
+            "createdAt": "2025-05-01T08:00:00.000Z",
+            "type": "scheduledNotePosted"
+        },
+        {
+            "id": "a5xk000000",
+            "createdAt": "2025-05-01T07:00:00.000Z",
+            "type": "achievementEarned",
+            "achievement": "notes1"
+        }
+    ]"#;
+
+    #[tokio::test]
+    async fn parses_notifications() {
+        let mut notifications: Vec<Notification> = serde_json::from_str(NOTIFICATIONS).unwrap();
+
+        let types = notifications.iter().map(|i| i.r#type).collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                NotificationType::reply,
+                NotificationType::follow,
+                NotificationType::unknown,
+                NotificationType::achievementEarned
+            ]
+        );
+        assert_eq!(notifications[3].achievement.as_deref(), Some("notes1"));
+        assert!(notifications[1].user.as_ref().unwrap().is_cat);
+
+        let client = Client::new("token".into(), "https://misskey.test".into());
+
+        //Without a note there's nothing to reply to
+        let follow = notifications.remove(1);
+        assert!(client.to_mention(follow).await.unwrap().is_none());
+
+        let mention = client
+            .to_mention(notifications.remove(0))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(mention.id, "a5xk3p0000");
+        assert_eq!(mention.status_id, "a5xk2z0000");
+        assert_eq!(mention.author.handle(), "fox@fox.example");
+        assert_eq!(mention.text.as_deref(), Some("@grok is this true?"));
+        assert_eq!(mention.visibility, fediverse::Visibility::Direct);
+        assert_eq!(mention.in_reply_to_id.as_deref(), Some("a5xk1y0000"));
+    }
+
+    ///Wraps `body` the way events of the main channel arrive
+    fn channel_message(channel: &str, r#type: &str, body: &serde_json::Value) -> String {
+        json!({
+            "type": "channel",
+            "body": { "id": channel, "type": r#type, "body": body },
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn parses_stream_events() {
+        let notifications: serde_json::Value = serde_json::from_str(NOTIFICATIONS).unwrap();
+        let notification = &notifications[0];
+        let note = &notification["note"];
+
+        assert!(matches!(
+            parse_stream_event(&channel_message(MAIN_CHANNEL_ID, "mention", note)),
+            Some(StreamEvent::Mention(n)) if n.id == "a5xk2z0000"
+        ));
+        assert!(matches!(
+            parse_stream_event(&channel_message(MAIN_CHANNEL_ID, "reply", note)),
+            Some(StreamEvent::Reply(n)) if n.id == "a5xk2z0000"
+        ));
+        assert!(matches!(
+            parse_stream_event(&channel_message(MAIN_CHANNEL_ID, "notification", notification)),
+            Some(StreamEvent::Notification(n)) if n.id == "a5xk3p0000"
+        ));
+
+        //Other channels, events the bot doesn't use and broken bodies are skipped
+        assert!(parse_stream_event(&channel_message("timeline", "mention", note)).is_none());
+        assert!(
+            parse_stream_event(&channel_message(
+                MAIN_CHANNEL_ID,
+                "unreadNotification",
+                note
+            ))
+            .is_none()
+        );
+        assert!(
+            parse_stream_event(&channel_message(MAIN_CHANNEL_ID, "mention", &json!({}))).is_none()
+        );
+        assert!(parse_stream_event(r#"{"type": "noteUpdated", "body": {}}"#).is_none());
+        assert!(parse_stream_event("not json").is_none());
+    }
+
+    #[tokio::test]
+    async fn subscribes_to_the_main_channel() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = Client::new(
+            "token".into(),
+            format!("http://{}", listener.local_addr().unwrap()),
+        );
+        let notifications: serde_json::Value = serde_json::from_str(NOTIFICATIONS).unwrap();
+        let mention = channel_message(MAIN_CHANNEL_ID, "mention", &notifications[0]["note"]);
+
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+            let Some(Ok(Message::Text(connect))) = socket.next().await else {
+                panic!("expected the channel to be connected");
+            };
+
+            socket.send(Message::Text(mention.into())).await.unwrap();
+            connect.to_string()
+        });
+
+        let items = client.stream().take(2).collect::<Vec<_>>().await;
+
+        let connect: serde_json::Value = serde_json::from_str(&server.await.unwrap()).unwrap();
+        assert_eq!(connect["type"], "connect");
+        assert_eq!(connect["body"]["channel"], "main");
+        assert_eq!(connect["body"]["id"], MAIN_CHANNEL_ID);
+
+        assert!(matches!(items[0], StreamItem::Connected));
+        assert!(
+            matches!(&items[1], StreamItem::Event(StreamEvent::Mention(n)) if n.id == "a5xk2z0000"),
+            "{items:?}"
+        );
+    }
+}
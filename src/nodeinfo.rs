@@ -0,0 +1,237 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::error::{ClientError, parse_response};
+use crate::fediverse::Backend;
+
+///How long to wait for the instance to answer
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Deserialize, Debug)]
+struct Link {
+    rel: String,
+    href: String,
+}
+
+///`/.well-known/nodeinfo`, pointing to the documents of every supported schema version
+#[derive(Deserialize, Debug)]
+struct Links {
+    links: Vec<Link>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Software {
+    ///Like `mastodon`, `misskey` or `sharkey`
+    pub name: String,
+    pub version: String,
+}
+
+#[derive(Deserialize, Default, Debug)]
+pub struct Metadata {
+    ///Feature flags, only sent by pleroma and akkoma, like `pleroma_emoji_reactions`
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+///The parts of a NodeInfo document the bot uses
+#[derive(Deserialize, Debug)]
+pub struct NodeInfo {
+    pub software: Software,
+    #[serde(default)]
+    pub metadata: Metadata,
+}
+
+///Server software the bot knows about, forks are grouped with the software they're based on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum Platform {
+    mastodon,
+    misskey,
+    sharkey,
+    ///Also calckey and iceshrimp
+    firefish,
+    ///Also akkoma
+    pleroma,
+    gotosocial,
+    other,
+}
+
+impl Platform {
+    pub fn from_name(name: &str) -> Self {
+        match name.to_lowercase().as_str() {
+            "mastodon" | "hometown" | "glitchsoc" => Self::mastodon,
+            "misskey" | "foundkey" | "cherrypick" => Self::misskey,
+            "sharkey" => Self::sharkey,
+            "firefish" | "calckey" | "iceshrimp" => Self::firefish,
+            //A rewrite of iceshrimp that only has the mastodon api
+            "iceshrimp.net" => Self::mastodon,
+            "pleroma" | "akkoma" => Self::pleroma,
+            "gotosocial" => Self::gotosocial,
+            _ => Self::other,
+        }
+    }
+
+    ///The api that works best with the software
+    pub fn backend(self) -> Backend {
+        match self {
+            Self::misskey | Self::sharkey | Self::firefish => Backend::misskey,
+            Self::pleroma => Backend::pleroma,
+            Self::mastodon | Self::gotosocial | Self::other => Backend::mastodon,
+        }
+    }
+}
+
+///Behaviour of the instance the bot has to work around
+#[derive(Debug, Clone, Copy)]
+pub struct Quirks {
+    ///Mentions can be streamed through the mastodon api
+    pub mastodon_streaming: bool,
+    ///Replies made of a single custom emoji break, so they have to be regenerated
+    pub lone_emoji_replies: bool,
+}
+
+impl Quirks {
+    ///Used when the software couldn't be detected, assumes the worst like before detection existed
+    pub const UNKNOWN: Self = Self {
+        mastodon_streaming: true,
+        lone_emoji_replies: true,
+    };
+}
+
+///What was detected about the instance
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub platform: Platform,
+    ///Name as reported by the instance, like `akkoma` for [`Platform::pleroma`]
+    pub software: String,
+    pub version: String,
+    pub features: Vec<String>,
+}
+
+impl Instance {
+    pub fn quirks(&self) -> Quirks {
+        match self.platform {
+            Platform::misskey | Platform::sharkey | Platform::firefish => Quirks {
+                mastodon_streaming: false,
+                lone_emoji_replies: true,
+            },
+            Platform::mastodon | Platform::pleroma | Platform::gotosocial => Quirks {
+                mastodon_streaming: true,
+                lone_emoji_replies: false,
+            },
+            Platform::other => Quirks::UNKNOWN,
+        }
+    }
+
+    pub fn has_feature(&self, feature: &str) -> bool {
+        self.features.iter().any(|i| i == feature)
+    }
+}
+
+impl From<NodeInfo> for Instance {
+    fn from(value: NodeInfo) -> Self {
+        Self {
+            platform: Platform::from_name(&value.software.name),
+            software: value.software.name,
+            version: value.software.version,
+            features: value.metadata.features,
+        }
+    }
+}
+
+///Fetches the NodeInfo document of the instance
+pub async fn fetch(instance: &str) -> Result<NodeInfo, ClientError> {
+    let client = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+
+    let links: Links = parse_response(
+        client
+            .get(instance.trim_end_matches('/').to_string() + "/.well-known/nodeinfo")
+            .send()
+            .await?,
+    )
+    .await?;
+
+    //The newest schema version, the software field is the same in all of them
+    let Some(link) = links
+        .links
+        .iter()
+        .filter(|i| {
+            i.rel
+                .starts_with("http://nodeinfo.diaspora.software/ns/schema/")
+        })
+        .max_by(|a, b| a.rel.cmp(&b.rel))
+    else {
+        return Err(ClientError::Status {
+            status: reqwest::StatusCode::NOT_FOUND,
+            code: None,
+            message: Some("the instance doesn't link a nodeinfo document".into()),
+        });
+    };
+
+    parse_response(client.get(&link.href).send().await?).await
+}
+
+///Detects the software of the instance
+pub async fn detect(instance: &str) -> Result<Instance, ClientError> {
+    Ok(fetch(instance).await?.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_the_platform() {
+        for (name, platform, backend) in [
+            ("mastodon", Platform::mastodon, Backend::mastodon),
+            ("Hometown", Platform::mastodon, Backend::mastodon),
+            ("misskey", Platform::misskey, Backend::misskey),
+            ("cherrypick", Platform::misskey, Backend::misskey),
+            ("sharkey", Platform::sharkey, Backend::misskey),
+            ("calckey", Platform::firefish, Backend::misskey),
+            ("iceshrimp", Platform::firefish, Backend::misskey),
+            ("iceshrimp.net", Platform::mastodon, Backend::mastodon),
+            ("akkoma", Platform::pleroma, Backend::pleroma),
+            ("Pleroma", Platform::pleroma, Backend::pleroma),
+            ("gotosocial", Platform::gotosocial, Backend::mastodon),
+            ("lemmy", Platform::other, Backend::mastodon),
+        ] {
+            assert_eq!(Platform::from_name(name), platform, "{name}");
+            assert_eq!(platform.backend(), backend, "{name}");
+        }
+    }
+
+    #[test]
+    fn reads_the_instance_from_nodeinfo() {
+        let nodeinfo: NodeInfo = serde_json::from_str(
+            r#"{
+                "version": "2.1",
+                "software": { "name": "akkoma", "version": "3.13.2", "repository": "" },
+                "protocols": ["activitypub"],
+                "usage": { "users": { "total": 1 }, "localPosts": 1 },
+                "openRegistrations": false,
+                "metadata": { "features": ["pleroma_emoji_reactions", "pleroma_chat_messages"] }
+            }"#,
+        )
+        .unwrap();
+
+        let instance = Instance::from(nodeinfo);
+
+        assert_eq!(instance.platform, Platform::pleroma);
+        assert_eq!(instance.software, "akkoma");
+        assert_eq!(instance.version, "3.13.2");
+        assert!(instance.has_feature("pleroma_emoji_reactions"));
+        assert!(!instance.has_feature("shareable_emoji_reactions"));
+        assert!(instance.quirks().mastodon_streaming);
+
+        //Not every software has metadata
+        let nodeinfo: NodeInfo =
+            serde_json::from_str(r#"{ "software": { "name": "sharkey", "version": "2025.4.3" } }"#)
+                .unwrap();
+        let instance = Instance::from(nodeinfo);
+
+        assert!(instance.features.is_empty());
+        assert!(!instance.quirks().mastodon_streaming);
+    }
+}
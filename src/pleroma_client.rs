@@ -0,0 +1,236 @@
+use async_trait::async_trait;
+use futures_util::StreamExt;
+use futures_util::stream::BoxStream;
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ClientError, check_response};
+use crate::fediverse::{self, FediverseClient, Payload};
+use crate::mastodon_client::{
+    self, Notification, NotificationType, NotificationsQuery, StreamEvent, StreamTransport,
+};
+use crate::request::{RateLimit, RequestLayer};
+use crate::streaming::StreamItem;
+
+///Pleroma and akkoma, which speak the mastodon api with some extensions. Everything that works
+///the same is left to the mastodon client
+#[derive(Clone)]
+pub struct Client {
+    token: String,
+    url: String,
+    client: RequestLayer,
+    mastodon: mastodon_client::Client,
+    ///Whether the instance has emoji reactions, posts are favourited instead if it doesn't
+    reactions: bool,
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct CreateChatMessage {
+    pub content: String,
+}
+
+impl Client {
+    pub fn new(token: String, url: String, reactions: bool) -> Self {
+        let client = RequestLayer::new();
+
+        Self {
+            mastodon: mastodon_client::Client::with_request_layer(
+                token.clone(),
+                url.clone(),
+                client.clone(),
+            ),
+            token,
+            url,
+            client,
+            reactions,
+        }
+    }
+
+    ///Reacts to the status with a unicode emoji or the shortcode of a custom one
+    pub async fn react_with_emoji(&self, id: String, emoji: String) -> Result<(), ClientError> {
+        let request = self
+            .client
+            .put(self.url.clone() + &reaction_path(&id, &emoji))
+            .bearer_auth(self.token.clone());
+
+        check_response(self.client.send(request).await?).await?;
+        Ok(())
+    }
+
+    pub async fn send_chat_message(
+        &self,
+        chat_id: String,
+        message: CreateChatMessage,
+    ) -> Result<(), ClientError> {
+        let request = self
+            .client
+            .post(self.url.clone() + &format!("/api/v1/pleroma/chats/{chat_id}/messages"))
+            .bearer_auth(self.token.clone())
+            .json(&message);
+
+        check_response(self.client.send_once(request).await?).await?;
+        Ok(())
+    }
+}
+
+fn reaction_path(id: &str, emoji: &str) -> String {
+    //Custom emoji are sent without the colons
+    format!(
+        "/api/v1/pleroma/statuses/{id}/reactions/{}",
+        emoji.trim_matches(':')
+    )
+}
+
+///Converts a mention or chat notification into a platform independent mention
+fn to_mention(notification: Notification) -> Option<fediverse::Mention> {
+    if notification.r#type != NotificationType::pleroma_chat_mention {
+        return mastodon_client::to_mention(notification);
+    }
+
+    let message = notification.chat_message?;
+
+    Some(fediverse::Mention {
+        id: notification.id,
+        status_id: message.id,
+        author: notification.account.into(),
+        text: message.content.as_deref().map(mastodon_client::strip_html),
+        //Chats are only seen by the two accounts in them
+        visibility: fediverse::Visibility::Direct,
+        mentions: Vec::new(),
+        in_reply_to_id: None,
+        chat_id: Some(message.chat_id),
+    })
+}
+
+fn chat_payload(chat_id: &str, text: String) -> Payload {
+    Payload {
+        endpoint: format!("/api/v1/pleroma/chats/{chat_id}/messages"),
+        body: serde_json::to_value(CreateChatMessage { content: text }).unwrap(),
+    }
+}
+
+#[async_trait]
+impl FediverseClient for Client {
+    async fn whoami(&self) -> Result<fediverse::Account, ClientError> {
+        self.mastodon.whoami().await
+    }
+
+    async fn fetch_mentions(
+        &self,
+        since_id: Option<String>,
+    ) -> Result<Vec<fediverse::Mention>, ClientError> {
+        let types = vec![
+            NotificationType::mention,
+            NotificationType::pleroma_chat_mention,
+        ];
+
+        let notifications = match since_id {
+            Some(id) => self.mastodon.get_notifications_since(id, &types).await?,
+            None => {
+                self.mastodon
+                    .get_notifications(NotificationsQuery {
+                        types,
+                        ..Default::default()
+                    })
+                    .await?
+            }
+        };
+
+        Ok(notifications.into_iter().filter_map(to_mention).collect())
+    }
+
+    async fn fetch_post(&self, id: String) -> Result<fediverse::Post, ClientError> {
+        self.mastodon.fetch_post(id).await
+    }
+
+    async fn fetch_thread(
+        &self,
+        mention: &fediverse::Mention,
+    ) -> Result<Vec<fediverse::Post>, ClientError> {
+        //Chat messages aren't part of threads
+        if mention.chat_id.is_some() {
+            return Ok(Vec::new());
+        }
+
+        self.mastodon.fetch_thread(mention).await
+    }
+
+    //Pleroma only streams through websockets
+    fn stream_mentions(&self) -> Option<BoxStream<'static, fediverse::MentionEvent>> {
+        let mentions = self
+            .mastodon
+            .stream_notifications(StreamTransport::WebSocket)
+            .filter_map(|item| async move {
+                match item {
+                    StreamItem::Connected => Some(fediverse::MentionEvent::Connected),
+                    StreamItem::Event(StreamEvent::Notification(n)) => {
+                        to_mention(*n).map(|m| fediverse::MentionEvent::Mention(Box::new(m)))
+                    }
+                    StreamItem::Event(_) => None,
+                }
+            });
+
+        Some(mentions.boxed())
+    }
+
+    async fn post(&self, text: String) -> Result<(), ClientError> {
+        self.mastodon.post(text).await
+    }
+
+    async fn reply(&self, mention: &fediverse::Mention, text: String) -> Result<(), ClientError> {
+        match &mention.chat_id {
+            Some(id) => {
+                self.send_chat_message(id.clone(), CreateChatMessage { content: text })
+                    .await
+            }
+            None => self.mastodon.reply(mention, text).await,
+        }
+    }
+
+    fn reply_payload(&self, mention: &fediverse::Mention, text: String) -> Payload {
+        match &mention.chat_id {
+            Some(id) => chat_payload(id, text),
+            None => self.mastodon.reply_payload(mention, text),
+        }
+    }
+
+    fn rate_limit(&self) -> RateLimit {
+        self.client.rate_limit()
+    }
+
+    async fn mark_read(&self, last_id: String) -> Result<(), ClientError> {
+        self.mastodon.mark_read(last_id).await
+    }
+
+    //Chat messages can't be reacted to, so the emoji is sent as a message instead
+    async fn react(
+        &self,
+        mention: &fediverse::Mention,
+        reaction: String,
+    ) -> Result<(), ClientError> {
+        if mention.chat_id.is_some() {
+            return self.reply(mention, reaction).await;
+        }
+
+        if !self.reactions {
+            return self.mastodon.react(mention, reaction).await;
+        }
+
+        self.react_with_emoji(mention.status_id.clone(), reaction)
+            .await
+    }
+
+    fn react_payload(&self, mention: &fediverse::Mention, reaction: String) -> Payload {
+        if mention.chat_id.is_some() {
+            return self.reply_payload(mention, reaction);
+        }
+
+        if !self.reactions {
+            return self.mastodon.react_payload(mention, reaction);
+        }
+
+        Payload {
+            endpoint: reaction_path(&mention.status_id, &reaction),
+            body: serde_json::Value::Null,
+        }
+    }
+}
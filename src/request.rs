@@ -0,0 +1,239 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use reqwest::{IntoUrl, RequestBuilder, Response, StatusCode};
+
+use crate::error::ClientError;
+
+///How many times a rate limited or failed request is retried
+const MAX_RETRIES: u32 = 5;
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+///Request budget reported by the server through the `X-RateLimit-*` headers
+#[derive(Default, Debug, Clone, Copy)]
+pub struct RateLimit {
+    ///How many requests can be made per period
+    pub limit: Option<u32>,
+    ///How many requests are left in the current period
+    pub remaining: Option<u32>,
+    ///When the budget gets refilled
+    pub reset: Option<SystemTime>,
+}
+
+impl RateLimit {
+    ///Whether less than a tenth of the budget is left
+    pub fn is_low(&self) -> bool {
+        match (self.limit, self.remaining) {
+            (Some(limit), Some(remaining)) => remaining * 10 < limit,
+            (_, Some(remaining)) => remaining == 0,
+            _ => false,
+        }
+    }
+
+    ///How long to wait before making another request, if the budget is exhausted
+    fn wait_time(&self) -> Option<Duration> {
+        if self.remaining != Some(0) {
+            return None;
+        }
+
+        self.reset
+            .and_then(|i| i.duration_since(SystemTime::now()).ok())
+            .map(|i| i.min(MAX_RETRY_DELAY))
+    }
+
+    fn update(&mut self, headers: &HeaderMap) {
+        let header = |name: &str| headers.get(name).and_then(|i| i.to_str().ok());
+
+        if let Some(limit) = header("X-RateLimit-Limit").and_then(|i| i.parse().ok()) {
+            self.limit = Some(limit);
+        }
+
+        if let Some(remaining) = header("X-RateLimit-Remaining").and_then(|i| i.parse().ok()) {
+            self.remaining = Some(remaining);
+        }
+
+        if let Some(reset) = header("X-RateLimit-Reset").and_then(parse_reset) {
+            self.reset = Some(reset);
+        }
+    }
+}
+
+///Mastodon sends the reset time as a timestamp, misskey as the number of seconds until it
+fn parse_reset(value: &str) -> Option<SystemTime> {
+    if let Ok(secs) = value.parse::<f64>() {
+        return Some(SystemTime::now() + Duration::from_secs_f64(secs.max(0.0)));
+    }
+
+    parse_timestamp(value)
+}
+
+///Parses an ISO 8601 UTC timestamp like `2025-07-01T12:05:00.000Z`, ignoring fractional seconds
+fn parse_timestamp(value: &str) -> Option<SystemTime> {
+    let (date, time) = value.trim_end_matches('Z').split_once('T')?;
+
+    let mut date = date.splitn(3, '-').map(|i| i.parse::<i64>());
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':').map(|i| i.parse::<i64>());
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+    //Days since the epoch, from http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+///Http client shared by the api clients that keeps track of the rate limit, waits for it to reset
+///when it runs out and retries requests that were rate limited or hit a server error
+#[derive(Clone, Default)]
+pub struct RequestLayer {
+    client: reqwest::Client,
+    rate_limit: Arc<Mutex<RateLimit>>,
+}
+
+impl RequestLayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.get(url)
+    }
+
+    pub fn post<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.post(url)
+    }
+
+    pub fn put<U: IntoUrl>(&self, url: U) -> RequestBuilder {
+        self.client.put(url)
+    }
+
+    ///Returns the last budget reported by the server
+    pub fn rate_limit(&self) -> RateLimit {
+        *self.rate_limit.lock().unwrap()
+    }
+
+    ///Sends the request, retrying it with a jittered exponential backoff if it gets rate limited or
+    ///hits a server error. The last response is returned as is once out of retries
+    pub async fn send(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+        self.send_with_retries(request, true).await
+    }
+
+    ///Like `send`, but server errors are only retried if the request has an `Idempotency-Key`.
+    ///For requests that create something, a proxy can time out after the server already did it
+    pub async fn send_once(&self, request: RequestBuilder) -> Result<Response, ClientError> {
+        self.send_with_retries(request, false).await
+    }
+
+    async fn send_with_retries(
+        &self,
+        request: RequestBuilder,
+        retry_server_errors: bool,
+    ) -> Result<Response, ClientError> {
+        let retry_server_errors = retry_server_errors
+            || request
+                .try_clone()
+                .and_then(|i| i.build().ok())
+                .is_some_and(|i| i.headers().contains_key("Idempotency-Key"));
+
+        let mut delay = MIN_RETRY_DELAY;
+        let mut attempt = 0;
+
+        loop {
+            let wait = self.rate_limit().wait_time();
+
+            if let Some(wait) = wait {
+                println!("Out of requests, waiting {}s", wait.as_secs());
+                tokio::time::sleep(wait).await;
+                //Don't wait again if the server doesn't report the new budget
+                self.rate_limit.lock().unwrap().remaining = None;
+            }
+
+            //Requests with streamed bodies can't be retried
+            let Some(r) = request.try_clone() else {
+                return Ok(request.send().await?);
+            };
+
+            let response = r.send().await?;
+
+            self.rate_limit.lock().unwrap().update(response.headers());
+
+            let status = response.status();
+
+            if !(status == StatusCode::TOO_MANY_REQUESTS
+                || (retry_server_errors && status.is_server_error()))
+                || attempt >= MAX_RETRIES
+            {
+                return Ok(response);
+            }
+
+            attempt += 1;
+
+            let retry_after = response
+                .headers()
+                .get("Retry-After")
+                .and_then(|i| i.to_str().ok())
+                .and_then(|i| i.parse::<f64>().ok())
+                .map(|i| Duration::from_secs_f64(i.max(0.0)));
+
+            let wait = match retry_after {
+                Some(r) => r.min(MAX_RETRY_DELAY),
+                //Anywhere between half and all of the delay, so clients don't retry in lockstep
+                None => delay.mul_f64(rand::rng().random_range(0.5..=1.0)),
+            };
+
+            println!(
+                "Request failed with {status}, retrying in {}s ({attempt}/{MAX_RETRIES})",
+                wait.as_secs()
+            );
+
+            tokio::time::sleep(wait).await;
+            delay = (delay * 2).min(MAX_RETRY_DELAY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00Z"), Some(UNIX_EPOCH));
+        assert_eq!(
+            parse_timestamp("2025-01-05T10:00:00.000Z"),
+            Some(UNIX_EPOCH + Duration::from_secs(1736071200))
+        );
+        //Leap day
+        assert_eq!(
+            parse_timestamp("2024-02-29T23:59:59Z"),
+            Some(UNIX_EPOCH + Duration::from_secs(1709251199))
+        );
+        assert_eq!(
+            parse_timestamp("2024-03-01T00:00:00.123456Z"),
+            Some(UNIX_EPOCH + Duration::from_secs(1709251200))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_timestamps() {
+        assert_eq!(parse_timestamp(""), None);
+        assert_eq!(parse_timestamp("60"), None);
+        assert_eq!(parse_timestamp("2025-01-05"), None);
+        assert_eq!(parse_timestamp("2025-01-05T10:00Z"), None);
+        assert_eq!(parse_timestamp("2025-xx-05T10:00:00Z"), None);
+        //Before the epoch
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z"), None);
+    }
+}
@@ -0,0 +1,992 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::fediverse::{MediaKind, Post};
+use crate::llm::{Llm, LlmOptions};
+use crate::markov::{Markov, MarkovOptions};
+
+///How deep templates can reference lists whose entries are templates themselves
+const MAX_TEMPLATE_DEPTH: u32 = 8;
+
+///How the response used for a reply is picked
+#[derive(Default, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+//For proper serialization
+#[allow(non_camel_case_types)]
+pub enum Selection {
+    ///Responses are tried in order, each one is used with its chance if its regex matches, if none
+    ///succeed the last one is used
+    #[default]
+    sequential,
+    ///One of the responses whose regex matches is picked, using the chances as weights. The chance
+    ///of a response is its chance divided by the sum of the chances of all matching responses
+    weighted,
+    ///The first response whose regex matches is used, chances are ignored
+    first_match,
+}
+
+///An entry of a dictionary, either just the word or the word with a weight.
+///
+///Words can be templates containing placeholders:
+///- `{author}` the author of the post
+///- `{name}` or `{random:name}` a random entry of the list `name` from `lists`, so `{emoji}` picks
+///  from the `emoji` list
+///- `{repeat:text:min..max}` `text` repeated between `min` and `max` times, inclusive
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(untagged)]
+pub enum Word {
+    Plain(String),
+    Weighted {
+        word: String,
+        ///How likely the word is compared to the others, plain words have a weight of 1
+        weight: u32,
+    },
+}
+
+impl From<&str> for Word {
+    fn from(value: &str) -> Self {
+        Self::Plain(value.into())
+    }
+}
+
+///A post in the thread a mention is part of
+#[derive(Default, Debug, Clone)]
+pub struct ThreadPost {
+    ///Handle of the author, including the @
+    pub author: String,
+    pub text: String,
+}
+
+///What a reply is generated for
+#[derive(Default, Debug, Clone)]
+pub struct Context {
+    pub text: Option<String>,
+    ///Handle of the author, including the @
+    pub author: String,
+    ///Posts the mention replied to, oldest first. Only fetched if a response needs them
+    pub thread: Vec<ThreadPost>,
+    ///Post the mention replied to. Only fetched if a response checks it
+    pub parent: Option<Post>,
+}
+
+///Checks on the post the mention replied to, a response with them is only used for replies whose
+///parent passes all of them
+#[derive(Default, Debug, Deserialize, Serialize, Clone)]
+pub struct ParentOptions {
+    ///Regex the text of the parent has to match
+    pub regex: Option<String>,
+    ///Regex the handle of the parent's author has to match, like `^user@instance\.social$`
+    pub author: Option<String>,
+    ///Whether the parent has to have attachments, or have none
+    pub media: Option<bool>,
+    ///Kind of attachment the parent has to have, `image`, `video`, `audio` or `other`
+    pub media_kind: Option<MediaKind>,
+    ///Whether the parent has to have a poll, or not have one
+    pub poll: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Response {
+    ///% chance that the bot will reply  with the following words, or its weight when using
+    ///weighted selection
+    pub chance: u32,
+    ///Checks the post against this regex and uses this dictionary if it matches
+    pub regex: Option<String>,
+    ///Minimum number of words the bot will respond with
+    pub min_words: u32,
+    ///Maximum number of words the bot will respond with
+    pub max_words: u32,
+    ///Wether the dictionary contains any emoji
+    pub contains_emoji: bool,
+    ///Dictionary of words the bot will reply with
+    ///
+    ///Note:
+    ///
+    ///With sequential selection order matters, the program will try to generate responses in
+    ///order, so chance percentages are a bit skewed. For example if the first response has a
+    ///chance of 10%, then 2nd has 15% and 3rd has 20%, the first one will have a chance of 10% then
+    ///the 2nd one will have 15% of 100 - 10%, and if both fail then the last one will be used. Use
+    ///weighted selection if the percentages should mean what they say.
+    #[serde(default)]
+    pub words: Vec<Word>,
+    ///Generate the reply with a markov chain instead of picking words, `min_words` and `max_words`
+    ///limit the length of the generated text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub markov: Option<MarkovOptions>,
+    ///Ask a language model for the reply, the words are used if it fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub llm: Option<LlmOptions>,
+    ///Only use this response if the post the mention replied to passes these checks
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<ParentOptions>,
+    ///React to the mention with the generated emoji instead of replying, needs `max_words = 1`.
+    ///Mastodon has no reactions and favourites the post instead
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub react: bool,
+}
+
+///Part of a parsed template
+#[derive(Debug, PartialEq)]
+pub enum Segment {
+    Text(String),
+    Author,
+    ///Random entry of a list
+    List(String),
+    Repeat {
+        text: String,
+        min: u32,
+        max: u32,
+    },
+}
+
+#[derive(Debug)]
+pub struct Entry {
+    pub weight: u32,
+    pub segments: Vec<Segment>,
+}
+
+///Parsed words with their weights
+#[derive(Debug)]
+pub struct WordList {
+    pub entries: Vec<Entry>,
+    ///Sum of all the weights
+    pub total_weight: u32,
+}
+
+impl WordList {
+    ///Parses the words, making sure every list they reference exists
+    fn compile(words: &[Word], lists: &HashMap<String, Vec<Word>>) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        let mut entries = Vec::new();
+
+        for w in words {
+            let (word, weight) = match w {
+                Word::Plain(w) => (w, 1),
+                Word::Weighted { word, weight } => (word, *weight),
+            };
+
+            match parse_template(word) {
+                Ok(segments) => {
+                    for i in &segments {
+                        if let Segment::List(name) = i
+                            && !lists.contains_key(name)
+                        {
+                            errors.push(format!("`{word}` uses the unknown list `{name}`"));
+                        }
+                    }
+
+                    entries.push(Entry { weight, segments });
+                }
+                Err(e) => errors.push(format!("`{word}`: {e}")),
+            }
+        }
+
+        let total_weight = entries.iter().map(|i| i.weight).sum();
+
+        if total_weight == 0 {
+            errors.push("words is empty or all the weights are 0".into());
+        }
+
+        if errors.is_empty() {
+            Ok(Self {
+                entries,
+                total_weight,
+            })
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn pick(&self, rng: &mut impl Rng) -> &Entry {
+        let mut roll = rng.random_range(0..self.total_weight);
+
+        for i in &self.entries {
+            if roll < i.weight {
+                return i;
+            }
+            roll -= i.weight;
+        }
+
+        unreachable!()
+    }
+}
+
+///Splits a word into text and placeholders
+fn parse_template(word: &str) -> Result<Vec<Segment>, String> {
+    let mut segments = Vec::new();
+    let mut rest = word;
+
+    while let Some(start) = rest.find('{') {
+        if start > 0 {
+            segments.push(Segment::Text(rest[..start].into()));
+        }
+
+        let Some(len) = rest[start..].find('}') else {
+            return Err("unclosed {".into());
+        };
+
+        let placeholder = &rest[start + 1..start + len];
+
+        segments.push(if placeholder == "author" {
+            Segment::Author
+        } else if let Some(repeat) = placeholder.strip_prefix("repeat:") {
+            let Some((text, range)) = repeat.rsplit_once(':') else {
+                return Err(format!(
+                    "{{{placeholder}}} should be {{repeat:text:min..max}}"
+                ));
+            };
+
+            let Some((min, max)) = range.split_once("..") else {
+                return Err(format!("invalid range `{range}`"));
+            };
+
+            let (Ok(min), Ok(max)) = (min.parse(), max.trim_start_matches('=').parse()) else {
+                return Err(format!("invalid range `{range}`"));
+            };
+
+            if min > max {
+                return Err(format!("invalid range `{range}`"));
+            }
+
+            Segment::Repeat {
+                text: text.into(),
+                min,
+                max,
+            }
+        } else {
+            let name = placeholder.strip_prefix("random:").unwrap_or(placeholder);
+            Segment::List(name.into())
+        });
+
+        rest = &rest[start + len + 1..];
+    }
+
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest.into()));
+    }
+
+    Ok(segments)
+}
+
+///Compiled checks on the parent post
+#[derive(Debug)]
+pub struct ParentCheck {
+    pub regex: Option<Regex>,
+    pub author: Option<Regex>,
+    pub media: Option<bool>,
+    pub media_kind: Option<MediaKind>,
+    pub poll: Option<bool>,
+}
+
+impl ParentCheck {
+    fn compile(options: &ParentOptions) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+
+        let mut regex = |name: &str, r: &Option<String>| match r.as_deref().map(Regex::new) {
+            Some(Ok(r)) => Some(r),
+            Some(Err(e)) => {
+                errors.push(format!("parent.{name}: invalid regex: {e}"));
+                None
+            }
+            None => None,
+        };
+
+        let check = Self {
+            regex: regex("regex", &options.regex),
+            author: regex("author", &options.author),
+            media: options.media,
+            media_kind: options.media_kind,
+            poll: options.poll,
+        };
+
+        if errors.is_empty() {
+            Ok(check)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn matches(&self, post: &Post) -> bool {
+        if let Some(regex) = &self.regex
+            && !post.text.as_deref().is_some_and(|i| regex.is_match(i))
+        {
+            return false;
+        }
+
+        if let Some(author) = &self.author
+            && !author.is_match(&post.author.handle())
+        {
+            return false;
+        }
+
+        if let Some(media) = self.media
+            && media == post.media.is_empty()
+        {
+            return false;
+        }
+
+        if let Some(kind) = self.media_kind
+            && !post.media.contains(&kind)
+        {
+            return false;
+        }
+
+        if let Some(poll) = self.poll
+            && poll != post.poll.is_some()
+        {
+            return false;
+        }
+
+        true
+    }
+}
+
+///A validated response with its regex compiled
+#[derive(Debug)]
+pub struct Rule {
+    pub chance: u32,
+    pub regex: Option<Regex>,
+    pub min_words: u32,
+    pub max_words: u32,
+    pub words: WordList,
+    pub markov: Option<Markov>,
+    pub llm: Option<Llm>,
+    pub parent: Option<ParentCheck>,
+    pub react: bool,
+}
+
+impl Rule {
+    ///Whether the regex matches the post and its parent passes the checks, rules without either
+    ///match everything
+    pub fn matches(&self, context: &Context) -> bool {
+        if let Some(parent) = &self.parent {
+            //Posts that aren't replies never pass
+            match &context.parent {
+                Some(post) if parent.matches(post) => {}
+                _ => return false,
+            }
+        }
+
+        match (&self.regex, &context.text) {
+            (None, _) => true,
+            (Some(regex), Some(text)) => regex.is_match(text),
+            //There's no post text so it will never match the regex
+            (Some(_), None) => false,
+        }
+    }
+}
+
+///Where the randomness used for replies comes from
+#[derive(Default, Debug)]
+pub enum RngSource {
+    ///A new random seed for every reply
+    #[default]
+    Entropy,
+    ///One generator seeded once, so the same mentions in the same order get the same replies
+    Seeded(Box<Mutex<StdRng>>),
+    ///A generator seeded from the seed and the post id, so a post always gets the same reply
+    PerStatus(u64),
+}
+
+impl RngSource {
+    pub fn new(seed: Option<u64>, seed_per_status: bool) -> Self {
+        match (seed, seed_per_status) {
+            (seed, true) => Self::PerStatus(seed.unwrap_or_default()),
+            (Some(seed), false) => Self::Seeded(Box::new(Mutex::new(StdRng::seed_from_u64(seed)))),
+            (None, false) => Self::Entropy,
+        }
+    }
+
+    ///Returns the generator to use for a reply to the post `status_id`
+    pub fn rng(&self, status_id: &str) -> StdRng {
+        match self {
+            Self::Entropy => StdRng::from_rng(&mut rand::rng()),
+            Self::Seeded(rng) => StdRng::from_rng(&mut *rng.lock().unwrap()),
+            Self::PerStatus(seed) => StdRng::seed_from_u64(hash_seed(*seed, status_id)),
+        }
+    }
+}
+
+///FNV-1a of the seed and the id, unlike the std hasher it's the same across rust versions so seeds
+///keep giving the same replies
+fn hash_seed(seed: u64, id: &str) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+
+    for b in seed.to_le_bytes().iter().chain(id.as_bytes()) {
+        hash ^= *b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+
+    hash
+}
+
+///The responses from the config, ready to generate replies
+#[derive(Debug)]
+pub struct Rules {
+    pub selection: Selection,
+    pub rules: Vec<Rule>,
+    ///Lists templates can pick from
+    pub lists: HashMap<String, WordList>,
+    pub rng: RngSource,
+}
+
+impl Rules {
+    ///Validates and compiles the responses, `lines` holds the line each response starts at in the
+    ///config file. Returns every problem found, not just the first one
+    pub fn compile(
+        responses: &[Response],
+        lists: &HashMap<String, Vec<Word>>,
+        selection: Selection,
+        lines: &[usize],
+    ) -> Result<Self, Vec<String>> {
+        if responses.is_empty() {
+            return Err(vec!["there must be at least one response".into()]);
+        }
+
+        let mut errors = Vec::new();
+        let mut rules = Vec::new();
+        let mut compiled_lists = HashMap::new();
+
+        for (name, words) in lists {
+            match WordList::compile(words, lists) {
+                Ok(l) => {
+                    compiled_lists.insert(name.clone(), l);
+                }
+                Err(e) => errors.extend(e.into_iter().map(|e| format!("lists.{name}: {e}"))),
+            }
+        }
+
+        for (ind, r) in responses.iter().enumerate() {
+            let mut error = |e: String| match lines.get(ind) {
+                Some(line) => errors.push(format!("responses[{ind}] (line {line}): {e}")),
+                None => errors.push(format!("responses[{ind}]: {e}")),
+            };
+
+            if r.chance > 100 && selection != Selection::weighted {
+                error(format!("chance is {}, but can be at most 100", r.chance));
+            }
+
+            if r.min_words > r.max_words {
+                error(format!(
+                    "min_words ({}) is greater than max_words ({})",
+                    r.min_words, r.max_words
+                ));
+            }
+
+            //Markov responses don't need any words
+            let words = if r.markov.is_some() && r.words.is_empty() {
+                Ok(WordList {
+                    entries: Vec::new(),
+                    total_weight: 0,
+                })
+            } else {
+                WordList::compile(&r.words, lists)
+            };
+
+            if let Err(e) = &words {
+                e.iter().for_each(|e| error(e.clone()));
+            }
+
+            let markov = match r.markov.as_ref().map(Markov::load).transpose() {
+                Ok(m) => m,
+                Err(e) => {
+                    error(format!("markov: {e}"));
+                    None
+                }
+            };
+
+            if r.markov.is_some() && r.llm.is_some() {
+                error("markov and llm can't be used together".into());
+            }
+
+            if r.react && r.max_words != 1 {
+                error(format!(
+                    "react needs max_words to be 1, but it's {}",
+                    r.max_words
+                ));
+            }
+
+            let llm = match r.llm.clone().map(Llm::new).transpose() {
+                Ok(l) => l,
+                Err(e) => {
+                    error(format!("llm: {e}"));
+                    None
+                }
+            };
+
+            let regex = match r.regex.as_deref().map(Regex::new).transpose() {
+                Ok(r) => r,
+                Err(e) => {
+                    error(format!("invalid regex: {e}"));
+                    None
+                }
+            };
+
+            let parent = match r.parent.as_ref().map(ParentCheck::compile).transpose() {
+                Ok(p) => p,
+                Err(e) => {
+                    e.into_iter().for_each(&mut error);
+                    None
+                }
+            };
+
+            rules.push(Rule {
+                chance: r.chance,
+                regex,
+                min_words: r.min_words,
+                max_words: r.max_words,
+                words: words.unwrap_or(WordList {
+                    entries: Vec::new(),
+                    total_weight: 0,
+                }),
+                markov,
+                llm,
+                parent,
+                react: r.react,
+            });
+        }
+
+        if errors.is_empty() {
+            Ok(Self {
+                selection,
+                rules,
+                lists: compiled_lists,
+                rng: RngSource::Entropy,
+            })
+        } else {
+            Err(errors)
+        }
+    }
+
+    ///Expands the entry's placeholders into `o`
+    fn expand(
+        &self,
+        entry: &Entry,
+        context: &Context,
+        rng: &mut impl Rng,
+        depth: u32,
+        o: &mut String,
+    ) {
+        for i in &entry.segments {
+            match i {
+                Segment::Text(t) => *o += t,
+                Segment::Author => *o += &context.author,
+                Segment::List(name) => {
+                    //Lists can reference each other, so stop instead of looping forever
+                    if depth >= MAX_TEMPLATE_DEPTH {
+                        continue;
+                    }
+
+                    let entry = self.lists[name].pick(rng);
+                    self.expand(entry, context, rng, depth + 1, o);
+                }
+                Segment::Repeat { text, min, max } => {
+                    for _ in 0..rng.random_range(*min..=*max) {
+                        *o += text;
+                    }
+                }
+            }
+        }
+    }
+
+    ///Whether any of the rules wants the thread of the mention
+    pub fn needs_thread(&self) -> bool {
+        self.rules
+            .iter()
+            .any(|i| i.llm.as_ref().is_some_and(Llm::include_thread))
+    }
+
+    ///Whether any of the rules checks the parent of the mention
+    pub fn needs_parent(&self) -> bool {
+        self.rules.iter().any(|i| i.parent.is_some())
+    }
+
+    ///Picks the index of the rule to reply to the post with, describing every step taken in
+    ///`trace`
+    pub fn pick_traced(
+        &self,
+        context: &Context,
+        rng: &mut impl Rng,
+        trace: &mut Vec<String>,
+    ) -> usize {
+        //Used when nothing else is picked
+        let last = self.rules.len() - 1;
+
+        match self.selection {
+            Selection::sequential => {
+                //The last dictionary is used regardless of any other checks
+                for (ind, r) in self.rules[..last].iter().enumerate() {
+                    //First try to get random
+                    let roll = rng.random_range(0..100);
+
+                    if roll >= r.chance {
+                        //Randomness check did not succeed<F
+                        trace.push(format!(
+                            "responses[{ind}]: rolled {roll}, not under the chance of {}",
+                            r.chance
+                        ));
+                        continue;
+                    }
+
+                    if r.matches(context) {
+                        trace.push(format!(
+                            "responses[{ind}]: rolled {roll}, within the chance of {}, and matched",
+                            r.chance
+                        ));
+                        return ind;
+                    }
+
+                    trace.push(format!(
+                        "responses[{ind}]: rolled {roll}, within the chance of {}, but didn't match",
+                        r.chance
+                    ));
+                }
+
+                trace.push(format!("responses[{last}]: used as the fallback"));
+                last
+            }
+            Selection::weighted => {
+                let matching = self
+                    .rules
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, i)| i.chance > 0 && i.matches(context))
+                    .collect::<Vec<_>>();
+
+                let total = matching.iter().map(|(_, i)| i.chance).sum::<u32>();
+
+                if total == 0 {
+                    trace.push(format!(
+                        "nothing matched, responses[{last}] used as the fallback"
+                    ));
+                    return last;
+                }
+
+                let mut roll = rng.random_range(0..total);
+
+                trace.push(format!(
+                    "matched {}, total weight {total}, rolled {roll}",
+                    matching
+                        .iter()
+                        .map(|(ind, i)| format!("responses[{ind}] ({})", i.chance))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+
+                for (ind, r) in matching {
+                    if roll < r.chance {
+                        trace.push(format!("responses[{ind}]: picked"));
+                        return ind;
+                    }
+                    roll -= r.chance;
+                }
+
+                unreachable!()
+            }
+            Selection::first_match => {
+                for (ind, r) in self.rules.iter().enumerate() {
+                    if r.matches(context) {
+                        trace.push(format!("responses[{ind}]: matched"));
+                        return ind;
+                    }
+
+                    trace.push(format!("responses[{ind}]: didn't match"));
+                }
+
+                trace.push(format!("responses[{last}]: used as the fallback"));
+                last
+            }
+        }
+    }
+}
+
+///Generates a reply to the post with the given rule, all the randomness comes from `rng`
+pub async fn generate_with(
+    rules: &Rules,
+    r: &Rule,
+    context: &Context,
+    rng: &mut (impl Rng + Send),
+) -> (String, u32) {
+    if let Some(llm) = &r.llm {
+        match llm.complete(context).await {
+            Ok(text) if !text.is_empty() => {
+                let num_words = text.split_whitespace().count() as u32;
+                return (text, num_words);
+            }
+            Ok(_) => println!("The model gave an empty reply, using the words instead"),
+            Err(e) => {
+                println!("Failed to get a reply from the model, using the words instead: {e}")
+            }
+        }
+    }
+
+    generate_words(rules, r, context, rng)
+}
+
+///Generates a reply with the rule's words or markov chain, never asking the model
+pub fn generate_words(
+    rules: &Rules,
+    r: &Rule,
+    context: &Context,
+    rng: &mut impl Rng,
+) -> (String, u32) {
+    if let Some(markov) = &r.markov {
+        return markov.generate(r.min_words, r.max_words, rng);
+    }
+
+    let num_words = rng.random_range(r.min_words..=r.max_words);
+
+    let mut o = String::new();
+
+    for _ in 0..num_words {
+        let entry = r.words.pick(rng);
+        rules.expand(entry, context, rng, 0, &mut o);
+        o += " ";
+    }
+
+    (o.trim_end().into(), num_words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_templates() {
+        assert_eq!(
+            parse_template("hi").unwrap(),
+            vec![Segment::Text("hi".into())]
+        );
+        assert_eq!(
+            parse_template("hi {author}!").unwrap(),
+            vec![
+                Segment::Text("hi ".into()),
+                Segment::Author,
+                Segment::Text("!".into())
+            ]
+        );
+        assert_eq!(
+            parse_template("{greetings}{random:emoji}").unwrap(),
+            vec![
+                Segment::List("greetings".into()),
+                Segment::List("emoji".into())
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_repeats() {
+        let repeat = |text: &str, min, max| Segment::Repeat {
+            text: text.into(),
+            min,
+            max,
+        };
+
+        assert_eq!(
+            parse_template("{repeat:a:1..3}").unwrap(),
+            vec![repeat("a", 1, 3)]
+        );
+        assert_eq!(
+            parse_template("{repeat:a:2..=2}").unwrap(),
+            vec![repeat("a", 2, 2)]
+        );
+        //Only the last colon separates the range
+        assert_eq!(
+            parse_template("{repeat::3:0..1}").unwrap(),
+            vec![repeat(":3", 0, 1)]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_templates() {
+        assert!(parse_template("{author").is_err());
+        assert!(parse_template("{repeat:a}").is_err());
+        assert!(parse_template("{repeat:a:3}").is_err());
+        assert!(parse_template("{repeat:a:x..3}").is_err());
+        assert!(parse_template("{repeat:a:3..1}").is_err());
+    }
+
+    ///Compiles the `responses` of a config
+    fn rules(config: &str, selection: Selection) -> Rules {
+        #[derive(Deserialize)]
+        struct Responses {
+            responses: Vec<Response>,
+        }
+
+        let config = toml::from_str::<Responses>(config).unwrap();
+        Rules::compile(&config.responses, &HashMap::new(), selection, &[]).unwrap()
+    }
+
+    const RESPONSES: &str = r#"
+        [[responses]]
+        chance = 30
+        min_words = 1
+        max_words = 8
+        contains_emoji = false
+        words = ["meow", "mew", "nyaaa", "mrrp"]
+
+        [[responses]]
+        chance = 50
+        min_words = 2
+        max_words = 6
+        contains_emoji = false
+        words = ["waf", "arrf", "awrfaf", "{repeat:!:1..3}"]
+
+        [[responses]]
+        chance = 100
+        min_words = 1
+        max_words = 10
+        contains_emoji = false
+        words = ["yes", "no", "maybe"]
+    "#;
+
+    ///Picks the rule and generates the words the way a reply does
+    fn reply(rules: &Rules, status_id: &str) -> (usize, String, u32) {
+        let context = Context {
+            text: Some("@grok is this true?".into()),
+            author: "@alice".into(),
+            ..Default::default()
+        };
+
+        let mut rng = rules.rng.rng(status_id);
+        let ind = rules.pick_traced(&context, &mut rng, &mut Vec::new());
+        let (words, count) = generate_words(rules, &rules.rules[ind], &context, &mut rng);
+
+        (ind, words, count)
+    }
+
+    #[test]
+    fn seed_per_status_repeats_replies() {
+        let mut first = rules(RESPONSES, Selection::sequential);
+        first.rng = RngSource::new(Some(42), true);
+        let mut second = rules(RESPONSES, Selection::sequential);
+        second.rng = RngSource::new(Some(42), true);
+
+        let ids = (0..20).map(|i| format!("1100{i}")).collect::<Vec<_>>();
+        let replies = ids.iter().map(|i| reply(&first, i)).collect::<Vec<_>>();
+
+        //The same post gets the same reply, no matter what was replied to before
+        for (id, expected) in ids.iter().zip(&replies).rev() {
+            assert_eq!(&reply(&second, id), expected);
+            assert_eq!(&reply(&first, id), expected);
+        }
+
+        //Different posts still get different replies
+        assert!(replies.iter().any(|i| i != &replies[0]));
+    }
+
+    #[test]
+    fn seed_repeats_the_sequence_of_replies() {
+        let mut first = rules(RESPONSES, Selection::weighted);
+        first.rng = RngSource::new(Some(42), false);
+        let mut second = rules(RESPONSES, Selection::weighted);
+        second.rng = RngSource::new(Some(42), false);
+
+        let replies = (0..20).map(|_| reply(&first, "1")).collect::<Vec<_>>();
+
+        for expected in &replies {
+            assert_eq!(&reply(&second, "1"), expected);
+        }
+
+        //The generator moves on after every reply
+        assert!(replies.iter().any(|i| i != &replies[0]));
+    }
+
+    ///A one word response, `regex` is left out if it's empty
+    fn response(chance: u32, regex: &str) -> String {
+        let regex = if regex.is_empty() {
+            String::new()
+        } else {
+            format!("regex = '{regex}'\n")
+        };
+
+        format!(
+            "[[responses]]\nchance = {chance}\n{regex}min_words = 1\nmax_words = 1\n\
+             contains_emoji = false\nwords = [\"w\"]\n"
+        )
+    }
+
+    ///How often every rule is picked for `runs` posts saying `text`
+    fn pick_counts(rules: &Rules, text: &str, runs: usize) -> Vec<usize> {
+        let context = Context {
+            text: Some(text.into()),
+            ..Default::default()
+        };
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut counts = vec![0; rules.rules.len()];
+
+        for _ in 0..runs {
+            counts[rules.pick_traced(&context, &mut rng, &mut Vec::new())] += 1;
+        }
+
+        counts
+    }
+
+    #[test]
+    fn sequential_chances_are_percentages() {
+        let quarter = rules(
+            &[response(25, ""), response(0, ""), response(100, "")].concat(),
+            Selection::sequential,
+        );
+
+        let counts = pick_counts(&quarter, "hi", 10000);
+
+        assert!((2350..2650).contains(&counts[0]), "{counts:?}");
+        assert_eq!(counts[1], 0);
+
+        //The first rule always wins if it can't fail
+        let certain = rules(
+            &[response(100, ""), response(100, "")].concat(),
+            Selection::sequential,
+        );
+        assert_eq!(pick_counts(&certain, "hi", 1000), [1000, 0]);
+    }
+
+    #[test]
+    fn sequential_skips_rules_that_dont_match() {
+        let rules = rules(
+            &[response(100, "^dog"), response(100, "")].concat(),
+            Selection::sequential,
+        );
+
+        assert_eq!(pick_counts(&rules, "cat", 100), [0, 100]);
+        assert_eq!(pick_counts(&rules, "dog", 100), [100, 0]);
+    }
+
+    #[test]
+    fn weighted_uses_chances_as_weights() {
+        let rules = rules(
+            &[
+                response(1, "cat"),
+                response(3, "cat"),
+                response(100, "dog"),
+                response(0, ""),
+            ]
+            .concat(),
+            Selection::weighted,
+        );
+
+        let counts = pick_counts(&rules, "cat", 10000);
+
+        assert!((2350..2650).contains(&counts[0]), "{counts:?}");
+        assert_eq!(counts[0] + counts[1], 10000);
+
+        //Only the fallback is left if nothing matches
+        assert_eq!(pick_counts(&rules, "bird", 100), [0, 0, 0, 100]);
+    }
+
+    #[test]
+    fn first_match_ignores_chances() {
+        let rules = rules(
+            &[response(0, "cat"), response(100, "")].concat(),
+            Selection::first_match,
+        );
+
+        assert_eq!(pick_counts(&rules, "cat", 100), [100, 0]);
+        assert_eq!(pick_counts(&rules, "dog", 100), [0, 100]);
+    }
+}
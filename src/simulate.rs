@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use crate::responses::{Context, Rules, generate_with, generate_words};
+
+///How many replies are generated per post without `--stats`
+const DEFAULT_SAMPLES: usize = 5;
+///How many replies are generated per post with `--stats`
+const DEFAULT_STATS_SAMPLES: usize = 10000;
+
+pub const USAGE: &str = "Usage: grok simulate [FILE] [-n COUNT] [--stats]
+\tReads posts from FILE, or stdin if it's missing, one per line, and shows how the bot would reply
+\tto them without connecting to the instance
+\t-h         displays this menu
+\t-n COUNT   how many replies to generate per post, 5 by default or 10000 with --stats
+\t--stats    only print how often each response was used and how long the replies were. The
+\t           model is never asked in this mode, responses using it fall back to their words";
+
+///Options of the simulate subcommand
+#[derive(Default, Debug)]
+pub struct Options {
+    ///Read from stdin if `None`
+    pub file: Option<String>,
+    pub samples: Option<usize>,
+    pub stats: bool,
+    pub help: bool,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-n" => {
+                    let count = args.next().ok_or("-n needs a count")?;
+                    options.samples = Some(
+                        count
+                            .parse()
+                            .map_err(|_| format!("invalid count `{count}`"))?,
+                    );
+                }
+                "--stats" => options.stats = true,
+                "-h" | "--help" => options.help = true,
+                a if a.starts_with('-') => return Err(format!("unknown option `{a}`")),
+                a => {
+                    if options.file.is_some() {
+                        return Err(format!("unexpected argument `{a}`"));
+                    }
+                    options.file = Some(a.into());
+                }
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+///Runs the posts through the rules and prints the results
+pub async fn run(rules: &Rules, options: &Options) -> Result<(), String> {
+    let input = match &options.file {
+        Some(f) => std::fs::read_to_string(f).map_err(|e| format!("{f}: {e}"))?,
+        None => {
+            let mut s = String::new();
+            std::io::stdin()
+                .read_to_string(&mut s)
+                .map_err(|e| format!("failed to read stdin: {e}"))?;
+            s
+        }
+    };
+
+    for text in input.lines().filter(|i| !i.trim().is_empty()) {
+        let context = Context {
+            text: Some(text.into()),
+            author: "@user".into(),
+            ..Default::default()
+        };
+
+        //Simulated posts have no id, so the text stands in for it when seeding per post
+        let mut rng = rules.rng.rng(text);
+
+        println!("Post: {text}");
+
+        if options.stats {
+            let samples = options.samples.unwrap_or(DEFAULT_STATS_SAMPLES);
+
+            //Rule to how many replies of each length it generated
+            let mut hits = BTreeMap::<usize, BTreeMap<u32, usize>>::new();
+
+            for _ in 0..samples {
+                let ind = rules.pick_traced(&context, &mut rng, &mut Vec::new());
+                let (_, words) = generate_words(rules, &rules.rules[ind], &context, &mut rng);
+
+                *hits.entry(ind).or_default().entry(words).or_default() += 1;
+            }
+
+            let percent = |count: usize, total: usize| count as f64 * 100.0 / total as f64;
+
+            for (ind, lengths) in hits {
+                let total = lengths.values().sum::<usize>();
+
+                println!(
+                    "  responses[{ind}]: {total} ({:.1}%)",
+                    percent(total, samples)
+                );
+
+                for (words, count) in lengths {
+                    println!("    {words} words: {:.1}%", percent(count, total));
+                }
+            }
+        } else {
+            for i in 1..=options.samples.unwrap_or(DEFAULT_SAMPLES) {
+                let mut trace = Vec::new();
+                let ind = rules.pick_traced(&context, &mut rng, &mut trace);
+                let (reply, _) = generate_with(rules, &rules.rules[ind], &context, &mut rng).await;
+
+                println!("  Sample {i}, responses[{ind}]:");
+
+                for step in trace {
+                    println!("    {step}");
+                }
+
+                println!("    -> {reply}");
+            }
+        }
+
+        println!();
+    }
+
+    Ok(())
+}
@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+///How many processed posts are remembered
+const MAX_PROCESSED: usize = 1000;
+
+///What the bot has already processed, persisted so it can resume after a restart
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub struct State {
+    ///Id of the newest notification that was processed
+    pub last_notification_id: Option<String>,
+    ///Ids of the posts that were already processed, oldest first
+    pub processed: VecDeque<String>,
+}
+
+impl State {
+    ///Loads the state from `path`, or returns an empty one if the file doesn't exist yet
+    pub fn load(path: &str) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(s) => serde_json::from_str(&s).map_err(|e| format!("{path}: {e}")),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("{path}: {e}")),
+        }
+    }
+
+    ///Writes the state to `path`
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let s = serde_json::to_string(self).map_err(|e| e.to_string())?;
+
+        //Write to a temporary file first so a crash never leaves a half written state
+        let tmp = format!("{path}.tmp");
+        std::fs::write(&tmp, s).map_err(|e| format!("{tmp}: {e}"))?;
+        std::fs::rename(&tmp, path).map_err(|e| format!("{path}: {e}"))
+    }
+
+    pub fn is_processed(&self, status_id: &str) -> bool {
+        self.processed.iter().any(|i| i == status_id)
+    }
+
+    ///Records that the notification and its post were processed
+    pub fn mark_processed(&mut self, notification_id: String, status_id: String) {
+        self.last_notification_id = Some(notification_id);
+
+        self.processed.push_back(status_id);
+
+        while self.processed.len() > MAX_PROCESSED {
+            self.processed.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("grok-test-{}-{name}.json", std::process::id()))
+            .to_string_lossy()
+            .into()
+    }
+
+    #[test]
+    fn missing_file_is_an_empty_state() {
+        let state = State::load(&temp_path("missing")).unwrap();
+
+        assert_eq!(state.last_notification_id, None);
+        assert!(state.processed.is_empty());
+    }
+
+    #[test]
+    fn survives_a_restart() {
+        let path = temp_path("saved");
+        let mut state = State::default();
+        state.mark_processed("10".into(), "a".into());
+        state.mark_processed("11".into(), "b".into());
+        state.save(&path).unwrap();
+
+        let loaded = State::load(&path);
+        let tmp_left = std::path::Path::new(&format!("{path}.tmp")).exists();
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+
+        assert!(!tmp_left);
+        assert_eq!(loaded.last_notification_id.as_deref(), Some("11"));
+        assert!(loaded.is_processed("a") && loaded.is_processed("b"));
+        assert!(!loaded.is_processed("c"));
+    }
+
+    #[test]
+    fn broken_file_is_an_error() {
+        let path = temp_path("broken");
+        std::fs::write(&path, "{").unwrap();
+
+        let loaded = State::load(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(loaded.unwrap_err().starts_with(&path));
+    }
+
+    #[test]
+    fn forgets_the_oldest_posts() {
+        let mut state = State::default();
+
+        for i in 0..MAX_PROCESSED + 10 {
+            state.mark_processed(i.to_string(), format!("post{i}"));
+        }
+
+        assert_eq!(state.processed.len(), MAX_PROCESSED);
+        assert!(!state.is_processed("post9"));
+        assert!(state.is_processed("post10"));
+        assert_eq!(
+            state.last_notification_id,
+            Some((MAX_PROCESSED + 9).to_string())
+        );
+    }
+}
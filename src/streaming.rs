@@ -0,0 +1,166 @@
+use std::time::Duration;
+
+use futures_util::{SinkExt, Stream, StreamExt};
+use reqwest::StatusCode;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream, connect_async};
+
+///How long a websocket can be silent before it gets pinged
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(120);
+
+pub type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub enum StreamError {
+    ///Retrying won't help
+    Fatal(String),
+    ///Couldn't connect
+    Failed(String),
+    ///Was connected but the connection dropped
+    Disconnected(String),
+}
+
+///What a reconnecting stream yields
+#[derive(Debug)]
+pub enum StreamItem<T> {
+    ///A connection was made, anything sent while there was none is missing from the stream
+    Connected,
+    Event(T),
+}
+
+///Calls `connect` until the stream is dropped or it fails for good, with a growing delay after
+///every failure. `connect` sends `Connected` once it's connected, then forwards events to the
+///sender until the connection drops
+pub fn reconnecting<T, F, Fut>(mut connect: F) -> impl Stream<Item = StreamItem<T>> + Send + 'static
+where
+    T: Send + 'static,
+    F: FnMut(mpsc::Sender<StreamItem<T>>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<(), StreamError>> + Send,
+{
+    let (tx, rx) = mpsc::channel(32);
+
+    tokio::spawn(async move {
+        let mut delay = MIN_RECONNECT_DELAY;
+
+        while !tx.is_closed() {
+            match connect(tx.clone()).await {
+                //Nobody is listening anymore
+                Ok(()) => break,
+                Err(StreamError::Fatal(e)) => {
+                    println!("Can't stream notifications: {e}");
+                    break;
+                }
+                Err(StreamError::Failed(e)) => {
+                    println!("Failed to connect to the streaming api: {e}");
+                }
+                Err(StreamError::Disconnected(e)) => {
+                    println!("Lost connection to the streaming api: {e}");
+                    delay = MIN_RECONNECT_DELAY;
+                }
+            }
+
+            if tx.is_closed() {
+                break;
+            }
+
+            println!("Reconnecting in {}s", delay.as_secs());
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    });
+
+    futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|event| (event, rx))
+    })
+}
+
+///Returns an error if the status means the server can't stream
+pub fn check_status(status: StatusCode) -> Result<(), StreamError> {
+    match status {
+        StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED => {
+            Err(StreamError::Fatal(format!(
+                "streaming is not supported ({status})"
+            )))
+        }
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+            Err(StreamError::Fatal(format!("not authorized ({status})")))
+        }
+        s if !s.is_success() && s != StatusCode::SWITCHING_PROTOCOLS => {
+            Err(StreamError::Failed(format!("unexpected status {status}")))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub async fn connect_websocket(url: &str) -> Result<Socket, StreamError> {
+    let socket = match connect_async(url).await {
+        Ok((s, _)) => s,
+        Err(tungstenite::Error::Http(response)) => {
+            check_status(response.status())?;
+            return Err(StreamError::Failed(format!(
+                "unexpected status {}",
+                response.status()
+            )));
+        }
+        Err(e) => return Err(StreamError::Failed(e.to_string())),
+    };
+
+    println!("Connected to the streaming api");
+    Ok(socket)
+}
+
+///Sends `Connected`, then forwards the text messages `parse` accepts until the connection fails
+///or the receiver is dropped, pinging the server whenever it goes quiet
+pub async fn read_websocket<T>(
+    socket: &mut Socket,
+    tx: &mpsc::Sender<StreamItem<T>>,
+    mut parse: impl FnMut(&str) -> Option<T>,
+) -> Result<(), StreamError> {
+    if tx.send(StreamItem::Connected).await.is_err() {
+        return Ok(());
+    }
+
+    let disconnected = |e: tungstenite::Error| StreamError::Disconnected(e.to_string());
+    let mut waiting_for_pong = false;
+
+    loop {
+        let message = match tokio::time::timeout(PING_INTERVAL, socket.next()).await {
+            Ok(Some(m)) => m.map_err(disconnected)?,
+            Ok(None) => return Err(StreamError::Disconnected("connection closed".into())),
+            Err(_) => {
+                if waiting_for_pong {
+                    return Err(StreamError::Disconnected("connection timed out".into()));
+                }
+
+                socket
+                    .send(Message::Ping(Default::default()))
+                    .await
+                    .map_err(disconnected)?;
+                waiting_for_pong = true;
+                continue;
+            }
+        };
+
+        //Anything coming through means the connection is alive
+        waiting_for_pong = false;
+
+        let text = match message {
+            Message::Text(t) => t,
+            Message::Close(_) => {
+                return Err(StreamError::Disconnected(
+                    "connection closed by the server".into(),
+                ));
+            }
+            _ => continue,
+        };
+
+        if let Some(event) = parse(&text)
+            && tx.send(StreamItem::Event(event)).await.is_err()
+        {
+            return Ok(());
+        }
+    }
+}